@@ -0,0 +1,60 @@
+use crate::output;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::num::NonZeroU64;
+use std::path::PathBuf;
+
+/// Bumped whenever the way the outputs are produced changes, so that entries
+/// written by an older version are never served.
+const CACHE_VERSION: &str = "1";
+
+/// Persistent cache of optimised images, stored as one file per entry in the
+/// `cacheDir` of a batch. Entries are keyed by the hash of the input image
+/// together with the effective `oxipng::Options` and zopfli iterations, so
+/// changing any option misses the cache. The cache is best effort: entries
+/// which can not be read or written are treated as misses.
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    pub fn new(dir: PathBuf) -> Self {
+        Cache { dir }
+    }
+
+    /// Key of the output of optimising `data` with `options`, followed by the
+    /// `zopfli` pass if any.
+    pub fn key(data: &[u8], options: &oxipng::Options, zopfli: Option<NonZeroU64>) -> String {
+        let options = format!("{:?} {:?}", options, zopfli);
+        hex_digest(&[CACHE_VERSION.as_bytes(), options.as_bytes(), data])
+    }
+
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        fs::read(self.path(key)).ok()
+    }
+
+    /// Stores `data` under `key`. Written atomically, so a concurrent batch
+    /// sharing the directory never reads a partial entry.
+    pub fn put(&self, key: &str, data: &[u8]) {
+        if fs::create_dir_all(&self.dir).is_ok() {
+            let _ = output::write_atomic(&self.path(key), data, None, None);
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.png", key))
+    }
+}
+
+/// Hex encoded SHA-256 digest of the concatenated `parts`.
+pub fn hex_digest(parts: &[&[u8]]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
@@ -0,0 +1,80 @@
+use neon::prelude::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Shared flag raised to stop the workers from picking up new entries, boxed
+/// so it can be handed to the `abort` listener of an `AbortSignal`.
+struct CancelFlag(Arc<AtomicBool>);
+
+impl Finalize for CancelFlag {}
+
+/// `abort` event listener registered on the `signal` of a batch. It is bound to
+/// the boxed `CancelFlag` of the batch, which is passed as the first argument.
+fn cancel_batch(mut cx: FunctionContext) -> JsResult<JsUndefined> {
+    let flag = cx.argument::<JsBox<CancelFlag>>(0)?;
+    flag.0.store(true, Ordering::SeqCst);
+    Ok(cx.undefined())
+}
+
+/// Listener registered on an `AbortSignal`, which has to be removed again once
+/// the batch is done so the signal does not keep it alive.
+pub struct AbortListener {
+    signal: Root<JsObject>,
+    listener: Root<JsFunction>,
+}
+
+impl AbortListener {
+    /// Raises `cancelled` as soon as `signal` is aborted, or right away if it
+    /// already is. Throws a `TypeError` if `signal` does not look like an
+    /// `AbortSignal`.
+    pub fn watch<'a, C: Context<'a>>(
+        cx: &mut C,
+        signal: Handle<'a, JsValue>,
+        cancelled: &Arc<AtomicBool>,
+    ) -> NeonResult<Self> {
+        let signal = match signal.downcast::<JsObject, _>(cx) {
+            Ok(signal) => signal,
+            Err(_) => return cx.throw_type_error("Option `signal` must be an AbortSignal"),
+        };
+        let aborted = signal.get_value(cx, "aborted")?;
+        let add_listener = signal.get_value(cx, "addEventListener")?;
+        let (aborted, add_listener) = match (
+            aborted.downcast::<JsBoolean, _>(cx),
+            add_listener.downcast::<JsFunction, _>(cx),
+        ) {
+            (Ok(aborted), Ok(add_listener)) => (aborted.value(cx), add_listener),
+            _ => return cx.throw_type_error("Option `signal` must be an AbortSignal"),
+        };
+        if aborted {
+            cancelled.store(true, Ordering::SeqCst);
+        }
+
+        // `JsFunction::new` only takes plain functions, so the flag is bound as
+        // the first argument of the listener.
+        let unbound = JsFunction::new(cx, cancel_batch)?;
+        let bind = unbound.get::<JsFunction, _, _>(cx, "bind")?;
+        let this = cx.undefined();
+        let flag = cx.boxed(CancelFlag(cancelled.clone()));
+        let listener = bind
+            .call(cx, unbound, vec![this.upcast(), flag.upcast()])?
+            .downcast_or_throw::<JsFunction, _>(cx)?;
+
+        let event = cx.string("abort");
+        add_listener.call(cx, signal, vec![event.upcast(), listener.upcast()])?;
+
+        Ok(AbortListener {
+            signal: Root::new(cx, &*signal),
+            listener: Root::new(cx, &*listener),
+        })
+    }
+
+    /// Removes the listener from the signal.
+    pub fn remove<'a, C: Context<'a>>(self, cx: &mut C) -> NeonResult<()> {
+        let signal = self.signal.into_inner(cx);
+        let listener = self.listener.into_inner(cx);
+        let remove_listener = signal.get::<JsFunction, _, _>(cx, "removeEventListener")?;
+        let event = cx.string("abort");
+        remove_listener.call(cx, signal, vec![event.upcast(), listener.upcast()])?;
+        Ok(())
+    }
+}
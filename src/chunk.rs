@@ -0,0 +1,129 @@
+/// Unsafe-to-copy ancillary chunks which do not depend on the pixel data, and
+/// so are still valid for an image with new pixels.
+const COLOR_INDEPENDENT_CHUNKS: &[&[u8]] = &[
+    b"cHRM", b"gAMA", b"iCCP", b"sRGB", b"cICP", b"mDCv", b"cLLi", b"tIME",
+];
+
+/// Splits a png into its raw chunks, each with its length, type, data and CRC.
+/// Returns `None` when the data is cut off in the middle of a chunk.
+pub fn split(png: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut rest = png.get(8..)?;
+    let mut chunks = vec![];
+    while !rest.is_empty() {
+        let length = rest.get(..4)?;
+        let length = u32::from_be_bytes([length[0], length[1], length[2], length[3]]);
+        let end = (length as usize).checked_add(12)?;
+        chunks.push(rest.get(..end)?);
+        rest = &rest[end..];
+    }
+    Some(chunks)
+}
+
+/// Type of a raw chunk, such as `IDAT`.
+pub fn name(chunk: &[u8]) -> &[u8] {
+    &chunk[4..8]
+}
+
+/// Data of a raw chunk.
+pub fn data(chunk: &[u8]) -> &[u8] {
+    &chunk[8..chunk.len() - 4]
+}
+
+/// Builds a raw chunk of the type `name` holding `data`.
+pub fn build(name: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(data.len() + 12);
+    chunk.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    chunk.extend_from_slice(name);
+    chunk.extend_from_slice(data);
+    let crc = crc32fast::hash(&chunk[4..]);
+    chunk.extend_from_slice(&crc.to_be_bytes());
+    chunk
+}
+
+/// Copies the ancillary chunks of the `original` png which are still valid for
+/// the re-encoded `image` into it, keeping them on the same side of the image
+/// data. The chunks named in `except` are left out as well.
+pub fn carry_over(original: &[u8], image: Vec<u8>, except: &[&[u8]]) -> Vec<u8> {
+    let (original_chunks, image_chunks) = match (split(original), split(&image)) {
+        (Some(original_chunks), Some(image_chunks)) => (original_chunks, image_chunks),
+        _ => return image,
+    };
+    let first_idat = original_chunks
+        .iter()
+        .position(|raw| name(raw) == b"IDAT")
+        .unwrap_or(original_chunks.len());
+    let (before_idat, after_idat) = original_chunks.split_at(first_idat);
+    let carried_over = |chunks: &[&[u8]]| -> Vec<u8> {
+        chunks
+            .iter()
+            .filter(|raw| is_carried_over(name(raw)) && !except.contains(&name(raw)))
+            .flat_map(|raw| raw.to_vec())
+            .collect()
+    };
+
+    let mut data = image[..8].to_vec();
+    for raw in image_chunks {
+        match name(raw) {
+            b"IHDR" => {
+                data.extend_from_slice(raw);
+                data.extend(carried_over(before_idat));
+            }
+            b"IEND" => {
+                data.extend(carried_over(after_idat));
+                data.extend_from_slice(raw);
+            }
+            _ => data.extend_from_slice(raw),
+        }
+    }
+    data
+}
+
+/// Ancillary chunks are kept when they are marked as safe to copy, which the
+/// fourth letter being lowercase tells, or known not to depend on the pixels.
+fn is_carried_over(name: &[u8]) -> bool {
+    name[0].is_ascii_lowercase()
+        && (name[3].is_ascii_lowercase() || COLOR_INDEPENDENT_CHUNKS.contains(&name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+    fn png(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = SIGNATURE.to_vec();
+        data.extend(chunks.concat());
+        data
+    }
+
+    #[test]
+    fn split_returns_raw_chunks() {
+        let chunks = [
+            build(b"IHDR", &[0; 13]),
+            build(b"IDAT", b"first"),
+            build(b"IDAT", b"second"),
+            build(b"IEND", &[]),
+        ];
+        let image = png(&chunks);
+        let split = split(&image).unwrap();
+        assert_eq!(split, chunks.iter().map(Vec::as_slice).collect::<Vec<_>>());
+        assert_eq!(name(split[2]), b"IDAT");
+        assert_eq!(data(split[2]), b"second");
+    }
+
+    #[test]
+    fn split_rejects_truncated_input() {
+        let data = png(&[build(b"IHDR", &[0; 13]), build(b"IEND", &[])]);
+        assert!(split(&data[..4]).is_none());
+        assert!(split(&data[..10]).is_none());
+        assert!(split(&data[..data.len() - 1]).is_none());
+        assert_eq!(split(SIGNATURE), Some(vec![]));
+    }
+
+    #[test]
+    fn build_computes_crc() {
+        let chunk = build(b"IEND", &[]);
+        assert_eq!(chunk, b"\0\0\0\0IEND\xae\x42\x60\x82");
+    }
+}
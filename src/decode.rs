@@ -0,0 +1,98 @@
+use png::{BitDepth, ColorType, Decoder, DecodingError, Limits, Transformations};
+
+/// Bytes an input image may take once decoded, enough for 64 megapixels of
+/// RGBA8. The png crate only counts its own buffers against its limits, so the
+/// frame is checked against it as well before it is allocated.
+pub const MAX_INPUT_BYTES: usize = 256 << 20;
+
+/// Where a decoded png comes from, which decides how large it may be.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Source {
+    /// Data read from the user which is worked on in memory, such as the input
+    /// of `resize` or `lossy`, taking several copies of the decoded image.
+    Input,
+    /// Image which oxipng optimised or wrote. oxipng does not limit the size
+    /// of the images, so a valid image it handled is never rejected here.
+    Optimized,
+}
+
+/// First frame of a png, expanded to 8 bit samples or more, with palettes and
+/// `tRNS` turned into colour and alpha samples.
+pub struct Frame {
+    pub width: u32,
+    pub height: u32,
+    pub color_type: ColorType,
+    pub bit_depth: BitDepth,
+    /// Whether the png is an animation, whose other frames are not decoded.
+    pub animated: bool,
+    /// Samples row by row, 16 bit ones in big endian byte order.
+    pub samples: Vec<u8>,
+}
+
+impl Frame {
+    /// Pixels as RGBA8, keeping the high byte of 16 bit samples.
+    pub fn rgba8(&self) -> Vec<[u8; 4]> {
+        self.pixels(u8::MAX, |sample| sample[0])
+    }
+
+    /// Pixels as RGBA16, with 8 bit samples widened to the full range.
+    pub fn rgba16(&self) -> Vec<[u16; 4]> {
+        self.pixels(u16::MAX, |sample| match *sample {
+            [high, low] => u16::from_be_bytes([high, low]),
+            [value] => u16::from(value) * 257,
+            _ => unreachable!("samples have 8 or 16 bits"),
+        })
+    }
+
+    fn pixels<T: Copy>(&self, max: T, sample: impl Fn(&[u8]) -> T) -> Vec<[T; 4]> {
+        let sample_bytes = match self.bit_depth {
+            BitDepth::Sixteen => 2,
+            _ => 1,
+        };
+        let channels = self.color_type.samples();
+        self.samples
+            .chunks_exact(sample_bytes * channels)
+            .map(|pixel| {
+                let mut values = [max; 4];
+                for (value, bytes) in values.iter_mut().zip(pixel.chunks_exact(sample_bytes)) {
+                    *value = sample(bytes);
+                }
+                match channels {
+                    1 => [values[0], values[0], values[0], max],
+                    2 => [values[0], values[0], values[0], values[1]],
+                    _ => values,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Decodes the first frame of the png `data`. Images from the `Input` which
+/// would take more than `MAX_INPUT_BYTES` fail with `LimitsExceeded`.
+pub fn frame(data: &[u8], source: Source) -> Result<Frame, DecodingError> {
+    let limits = match source {
+        Source::Input => Limits {
+            bytes: MAX_INPUT_BYTES,
+        },
+        Source::Optimized => Limits { bytes: usize::MAX },
+    };
+    let mut decoder = Decoder::new_with_limits(data, limits);
+    decoder.set_transformations(Transformations::EXPAND);
+    let mut reader = decoder.read_info()?;
+    if reader.output_buffer_size() > limits.bytes {
+        return Err(DecodingError::LimitsExceeded);
+    }
+    let (color_type, bit_depth) = reader.output_color_type();
+    let animated = reader.info().animation_control.is_some();
+    let mut samples = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut samples)?;
+    samples.truncate(info.line_size * info.height as usize);
+    Ok(Frame {
+        width: info.width,
+        height: info.height,
+        color_type,
+        bit_depth,
+        animated,
+        samples,
+    })
+}
@@ -0,0 +1,206 @@
+use crate::chunk;
+use miniz_oxide::inflate::decompress_to_vec_zlib;
+use std::io::Write;
+use std::num::NonZeroU64;
+use std::time::{Duration, Instant};
+
+/// Size of the pieces the image data is handed to zopfli in. The time budget
+/// is checked between pieces, larger ones compress slightly better.
+const PIECE_SIZE: usize = 64 * 1024;
+/// Estimated time zopfli takes for every byte of image data, in nanoseconds,
+/// plus `NANOS_PER_BYTE_ITERATION` for each of its iterations. Only used until
+/// the first piece is compressed, after which its actual pace takes over.
+const NANOS_PER_BYTE: u64 = 2000;
+const NANOS_PER_BYTE_ITERATION: u64 = 400;
+
+/// Deflates the image data of the optimised `png` again with zopfli, running
+/// `iterations` of its optimisation passes. The filters oxipng picked are kept,
+/// only the compression of the `IDAT` stream changes. Returns the new png when
+/// it is smaller, `None` otherwise or when the pass would not end before the
+/// `deadline`.
+pub fn recompress(
+    png: &[u8],
+    iterations: NonZeroU64,
+    deadline: Option<Instant>,
+) -> Option<Vec<u8>> {
+    let chunks = chunk::split(png)?;
+    let idat: Vec<u8> = chunks
+        .iter()
+        .filter(|chunk| chunk::name(chunk) == b"IDAT")
+        .flat_map(|chunk| chunk::data(chunk).iter().copied())
+        .collect();
+    let filtered = decompress_to_vec_zlib(&idat).ok()?;
+    let deflated = deflate(&filtered, iterations, deadline)?;
+    if deflated.len() >= idat.len() {
+        return None;
+    }
+
+    // The image data is written as a single `IDAT` chunk in place of the first
+    // one.
+    let mut data = png[..8].to_vec();
+    let mut written = false;
+    for raw in chunks {
+        if chunk::name(raw) != b"IDAT" {
+            data.extend_from_slice(raw);
+        } else if !written {
+            data.extend(chunk::build(b"IDAT", &deflated));
+            written = true;
+        }
+    }
+    Some(data)
+}
+
+/// Compresses `data` into a zlib stream with zopfli, one `PIECE_SIZE` piece at
+/// a time. Before every piece, gives up with `None` when the rest of the data
+/// would not be compressed before the `deadline`.
+fn deflate(data: &[u8], iterations: NonZeroU64, deadline: Option<Instant>) -> Option<Vec<u8>> {
+    let options = zopfli::Options {
+        iteration_count: iterations,
+        ..zopfli::Options::default()
+    };
+    let mut encoder = zopfli::ZlibEncoder::new(options, zopfli::BlockType::Dynamic, vec![]).ok()?;
+    let start = Instant::now();
+    let mut compressed = 0;
+    for piece in data.chunks(PIECE_SIZE) {
+        let remaining = data.len() - compressed;
+        let needed = match compressed {
+            0 => estimate(remaining, iterations),
+            _ => start
+                .elapsed()
+                .mul_f64(remaining as f64 / compressed as f64),
+        };
+        let time_left = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        if time_left.is_some_and(|time_left| needed > time_left) {
+            return None;
+        }
+        // zopfli holds a piece back until the next write. The empty write has
+        // it compressed right away, so the encoder is left with nothing to
+        // compress when it is dropped after giving up, or finished.
+        if encoder.write(piece).is_err() || encoder.write(&[]).is_err() {
+            return None;
+        }
+        compressed += piece.len();
+    }
+    encoder.finish().ok()
+}
+
+/// Time zopfli is expected to take for `len` bytes of image data before any of
+/// it was compressed.
+fn estimate(len: usize, iterations: NonZeroU64) -> Duration {
+    let nanos_per_byte =
+        NANOS_PER_BYTE.saturating_add(iterations.get().saturating_mul(NANOS_PER_BYTE_ITERATION));
+    Duration::from_nanos(nanos_per_byte.saturating_mul(len as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use miniz_oxide::deflate::compress_to_vec_zlib;
+    use std::time::Duration;
+
+    const ITERATIONS: NonZeroU64 = match NonZeroU64::new(5) {
+        Some(iterations) => iterations,
+        None => unreachable!(),
+    };
+
+    /// 64x64 rgb gradient, with its filtered data cheaply deflated and spread
+    /// over `idats` chunks.
+    fn gradient(idats: usize) -> Vec<u8> {
+        let mut ihdr = vec![];
+        ihdr.extend_from_slice(&64u32.to_be_bytes());
+        ihdr.extend_from_slice(&64u32.to_be_bytes());
+        ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+        let filtered: Vec<u8> = (0..64u8)
+            .flat_map(|y| {
+                std::iter::once(0).chain((0..64u8).flat_map(move |x| [x * 4, y * 4, x + y]))
+            })
+            .collect();
+        let deflated = compress_to_vec_zlib(&filtered, 1);
+
+        let mut data = b"\x89PNG\r\n\x1a\n".to_vec();
+        data.extend(chunk::build(b"IHDR", &ihdr));
+        data.extend(chunk::build(b"tEXt", b"Comment\0before"));
+        for piece in deflated.chunks(deflated.len() / idats + 1) {
+            data.extend(chunk::build(b"IDAT", piece));
+        }
+        data.extend(chunk::build(b"IEND", &[]));
+        data
+    }
+
+    fn decode(data: &[u8]) -> Vec<u8> {
+        let mut reader = png::Decoder::new(data).read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut pixels).unwrap();
+        pixels
+    }
+
+    fn idats(data: &[u8]) -> usize {
+        chunk::split(data)
+            .unwrap()
+            .iter()
+            .filter(|raw| chunk::name(raw) == b"IDAT")
+            .count()
+    }
+
+    #[test]
+    fn recompress_joins_idats() {
+        let original = gradient(4);
+        assert_eq!(idats(&original), 4);
+        let recompressed = recompress(&original, ITERATIONS, None).unwrap();
+        assert!(recompressed.len() < original.len());
+        assert_eq!(idats(&recompressed), 1);
+        let names: Vec<&[u8]> = chunk::split(&recompressed)
+            .unwrap()
+            .into_iter()
+            .map(chunk::name)
+            .collect();
+        assert_eq!(names, [&b"IHDR"[..], b"tEXt", b"IDAT", b"IEND"]);
+    }
+
+    #[test]
+    fn recompress_round_trips() {
+        let original = gradient(1);
+        let recompressed = recompress(&original, ITERATIONS, None).unwrap();
+        assert_eq!(decode(&recompressed), decode(&original));
+    }
+
+    #[test]
+    fn recompress_rejects_truncated_input() {
+        let original = gradient(2);
+        assert!(recompress(&original[..original.len() - 20], ITERATIONS, None).is_none());
+        assert!(recompress(&original[..40], ITERATIONS, None).is_none());
+    }
+
+    #[test]
+    fn recompress_gives_up_past_deadline() {
+        let deadline = Instant::now() - Duration::from_millis(1);
+        assert!(recompress(&gradient(1), ITERATIONS, Some(deadline)).is_none());
+    }
+
+    #[test]
+    fn recompress_gives_up_on_short_deadline() {
+        let start = Instant::now();
+        let deadline = start + Duration::from_millis(1);
+        assert!(recompress(&gradient(1), ITERATIONS, Some(deadline)).is_none());
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn recompress_runs_within_long_deadline() {
+        let deadline = Instant::now() + Duration::from_secs(600);
+        let original = gradient(1);
+        let recompressed = recompress(&original, ITERATIONS, Some(deadline)).unwrap();
+        assert_eq!(decode(&recompressed), decode(&original));
+    }
+
+    #[test]
+    fn recompress_round_trips_many_pieces() {
+        let original = gradient(1);
+        let filtered = decompress_to_vec_zlib(chunk::data(chunk::split(&original).unwrap()[2]))
+            .unwrap()
+            .repeat(12);
+        let deflated = deflate(&filtered, ITERATIONS, None).unwrap();
+        assert!(filtered.len() > 2 * PIECE_SIZE);
+        assert_eq!(decompress_to_vec_zlib(&deflated).unwrap(), filtered);
+    }
+}
@@ -0,0 +1,90 @@
+use neon::prelude::*;
+use std::fmt;
+use std::io;
+
+/// Error raised while compressing a single png file. Every error carries a
+/// stable `code` which is exposed to JS on the `Error` object, so that callers
+/// can tell the failures apart without parsing the message.
+#[derive(Clone, Debug)]
+pub struct CompressError {
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl CompressError {
+    pub fn new(code: &'static str, message: impl Into<String>) -> Self {
+        CompressError {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// Error raised when the input file can not be read.
+    pub fn read(path: &str, err: io::Error) -> Self {
+        let code = match err.kind() {
+            io::ErrorKind::NotFound => "ERR_FILE_NOT_FOUND",
+            io::ErrorKind::PermissionDenied => "ERR_PERMISSION_DENIED",
+            _ => "ERR_READ_FAILED",
+        };
+        CompressError::new(code, format!("Unable to read file {}: {}", path, err))
+    }
+
+    /// Error raised when the output file can not be written.
+    pub fn write(path: &str, err: io::Error) -> Self {
+        let code = match err.kind() {
+            io::ErrorKind::PermissionDenied => "ERR_PERMISSION_DENIED",
+            _ => "ERR_WRITE_FAILED",
+        };
+        CompressError::new(code, format!("Unable to write file {}: {}", path, err))
+    }
+
+    /// Error raised when the directory of the output file can not be created.
+    pub fn create_dir(path: &str, err: io::Error) -> Self {
+        let code = match err.kind() {
+            io::ErrorKind::PermissionDenied => "ERR_PERMISSION_DENIED",
+            _ => "ERR_CREATE_DIR_FAILED",
+        };
+        CompressError::new(
+            code,
+            format!("Unable to create directory {}: {}", path, err),
+        )
+    }
+
+    /// Creates a JS `Error` object with the message and the `code` property set.
+    pub fn to_js_error<'a, C: Context<'a>>(&self, cx: &mut C) -> JsResult<'a, JsError> {
+        let err = cx.error(&self.message)?;
+        let code = cx.string(self.code);
+        err.set(cx, "code", code)?;
+        Ok(err)
+    }
+}
+
+impl fmt::Display for CompressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+impl From<oxipng::PngError> for CompressError {
+    fn from(err: oxipng::PngError) -> Self {
+        let code = match &err {
+            oxipng::PngError::NotPNG => "ERR_NOT_PNG",
+            oxipng::PngError::APNGNotSupported => "ERR_APNG_UNSUPPORTED",
+            oxipng::PngError::TimedOut => "ERR_TIMED_OUT",
+            oxipng::PngError::InvalidData => "ERR_INVALID_DATA",
+            oxipng::PngError::TruncatedData => "ERR_TRUNCATED_DATA",
+            oxipng::PngError::ChunkMissing(_) => "ERR_CHUNK_MISSING",
+            oxipng::PngError::DeflatedDataTooLong(_) => "ERR_COMPRESSION_FAILED",
+            // oxipng reports a bad signature through the generic variant, so the
+            // message is the only way to tell it apart from other failures.
+            oxipng::PngError::Other(message)
+                if message.starts_with("Invalid PNG header")
+                    || message.starts_with("Not a PNG file") =>
+            {
+                "ERR_NOT_PNG"
+            }
+            _ => "ERR_COMPRESSION_FAILED",
+        };
+        CompressError::new(code, err.to_string())
+    }
+}
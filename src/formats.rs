@@ -0,0 +1,63 @@
+use crate::decode::{self, Source};
+use crate::error::CompressError;
+use crate::options::{Avif, Webp};
+use ravif::{Img, RGBA8};
+
+/// Encodes the png `data` as WebP with the `options`, failing with
+/// `ERR_ENCODE_FAILED` if libwebp can not encode it, e.g. as it is larger
+/// than the 16383x16383 pixels WebP supports.
+pub fn webp(data: &[u8], options: &Webp) -> Result<Vec<u8>, CompressError> {
+    let (width, height, rgba) = decode(data, "WebP")?;
+    let rgba = rgba.concat();
+    let encoder = webp::Encoder::from_rgba(&rgba, width, height);
+    let mut config = webp::WebPConfig::new().map_err(|()| {
+        CompressError::new("ERR_ENCODE_FAILED", "Unable to set up the WebP encoder")
+    })?;
+    config.lossless = i32::from(options.lossless);
+    // In lossless mode the quality is the effort spent on compressing.
+    config.quality = options.quality;
+    config.alpha_compression = i32::from(!options.lossless);
+    // Keep the color of transparent pixels, the same as the png does.
+    config.exact = i32::from(options.lossless);
+    let encoded = encoder.encode_advanced(&config).map_err(|err| {
+        CompressError::new(
+            "ERR_ENCODE_FAILED",
+            format!("Unable to encode WebP: {:?}", err),
+        )
+    })?;
+    Ok(encoded.to_vec())
+}
+
+/// Encodes the png `data` as AVIF with the `options`, using the pure Rust
+/// rav1e encoder. Fails with `ERR_ENCODE_FAILED` if it can not be encoded.
+pub fn avif(data: &[u8], options: &Avif) -> Result<Vec<u8>, CompressError> {
+    let (width, height, rgba) = decode(data, "AVIF")?;
+    let pixels: Vec<RGBA8> = rgba
+        .into_iter()
+        .map(|[red, green, blue, alpha]| RGBA8::new(red, green, blue, alpha))
+        .collect();
+    let encoded = ravif::Encoder::new()
+        .with_quality(options.quality)
+        .with_alpha_quality(options.quality)
+        .with_speed(options.speed)
+        .encode_rgba(Img::new(&pixels[..], width as usize, height as usize))
+        .map_err(|err| {
+            CompressError::new(
+                "ERR_ENCODE_FAILED",
+                format!("Unable to encode AVIF: {}", err),
+            )
+        })?;
+    Ok(encoded.avif_file)
+}
+
+/// Decodes the png `data` into RGBA8 pixels to encode them as `format`, which
+/// only have 8 bit samples.
+fn decode(data: &[u8], format: &str) -> Result<(u32, u32, Vec<[u8; 4]>), CompressError> {
+    let frame = decode::frame(data, Source::Optimized).map_err(|err| {
+        CompressError::new(
+            "ERR_ENCODE_FAILED",
+            format!("Unable to decode the png for {}: {}", format, err),
+        )
+    })?;
+    Ok((frame.width, frame.height, frame.rgba8()))
+}
@@ -1,129 +1,1187 @@
+mod cache;
+mod cancel;
+mod chunk;
+mod decode;
+mod deflate;
+mod error;
+mod formats;
+mod manifest;
+mod options;
+mod output;
+mod pool;
+mod progress;
+mod quantize;
+mod resize;
+#[cfg(test)]
+mod test_dir;
+mod verify;
+mod walk;
+
+use cache::Cache;
+use cancel::AbortListener;
+use error::CompressError;
+use manifest::Manifest;
 use neon::prelude::*;
-use oxipng;
-use std::path::PathBuf;
+use neon::types::buffer::TypedArray;
+use neon::types::Deferred;
+use options::{Avif, BatchOptions, CompressOptions, Format, Lossy, MinSavings, Resize, Webp};
+use std::borrow::Cow;
+use std::fs;
+use std::num::NonZeroU64;
+use std::path::Path;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Keys accepted on the entries of the task array.
+const TASK_KEYS: &[&str] = &["in", "out", "options"];
 
 struct CompressTask {
     input: String,
     out: String,
+    options: oxipng::Options,
+    min_savings: Option<MinSavings>,
+    /// Replace `input` atomically, in which case `out` is the same path.
+    in_place: bool,
+    preserve_mtime: bool,
+    create_dirs: bool,
+    incremental: bool,
+    verify: bool,
+    lossy: Option<Lossy>,
+    /// Iterations of the zopfli pass following oxipng, if any.
+    zopfli: Option<NonZeroU64>,
+    /// Formats written beside `out`.
+    formats: Vec<Format>,
+    webp: Webp,
+    avif: Avif,
+    resize: Option<Resize>,
+}
+
+impl CompressTask {
+    /// Task compressing `input` into `out` with the merged `options`.
+    fn new(input: String, out: String, options: &CompressOptions) -> Self {
+        CompressTask {
+            input,
+            out,
+            options: options.to_oxipng(),
+            min_savings: options.min_savings,
+            in_place: options.in_place.unwrap_or(false),
+            preserve_mtime: options.preserve_mtime.unwrap_or(false),
+            create_dirs: options.create_dirs.unwrap_or(true),
+            incremental: options.incremental.unwrap_or(false),
+            verify: options.verify.unwrap_or(false),
+            lossy: options.lossy.flatten(),
+            zopfli: options.zopfli_iterations(),
+            formats: options.formats.clone().unwrap_or_default(),
+            webp: options.webp.unwrap_or_default(),
+            avif: options.avif.unwrap_or_default(),
+            resize: options.resize,
+        }
+    }
+
+    /// Path the output in `format` is written to, beside `out`.
+    fn format_path(&self, format: Format) -> String {
+        Path::new(&self.out)
+            .with_extension(format.name())
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+/// Output of an entry in one of its extra `formats`.
+#[derive(Clone)]
+struct FormatOutput {
+    format: Format,
+    out: String,
+    bytes: Option<u64>,
+}
+
+/// State shared by every entry of a running batch.
+#[derive(Default)]
+struct BatchContext {
+    /// Cache of optimised images to go through, if any.
+    cache: Option<Arc<Cache>>,
+    /// Manifest of the `incremental` mode, if any.
+    manifest: Option<Manifest>,
+    /// Run the pipeline without writing any output.
+    dry_run: bool,
 }
 
-/// Perform png image compression using oxipng. It takes the input file name and
-/// the output filename as argument and executes the default oxipng compression
-/// logic.
+/// Outcome of the compression of a single `CompressTask`, handed back to JS as
+/// an object of the following structure.
+///
+/// {
+///  in: "string",
+///  out: "string",
+///  status: "done" | "error" | "cancelled",
+///  inputBytes: number | null,
+///  outputBytes: number | null,
+///  savedBytes: number | null,
+///  durationMs: number,
+///  action: "optimized" | "copied" | "skipped" | "upToDate" | null,
+///  cache: "hit" | "miss" | null,
+///  quality: number | null,
+///  formats: { [format]: { out: "string", bytes: number | null } } | null,
+///  error: Error | null
+/// }
+///
+/// The `action` tells what was written to `out`: the optimised image, a copy of
+/// the input because the `minSavings` threshold was not met, or nothing as
+/// `out` already held the input or was up to date in `incremental` mode. It is
+/// `null` unless the status is "done", the same as `cache`, which tells whether
+/// the optimised image was taken from the `cacheDir` of the batch and is `null`
+/// without one. The `quality` of the written image is set when it was
+/// quantised by the `lossy` mode. With extra `formats` the `formats` object
+/// holds the path and size of the output in every format, including the png,
+/// and is `null` otherwise. The `error` is a JS `Error` with a `code` property,
+/// see `CompressError`.
+#[derive(Clone)]
+struct CompressResult {
+    input: String,
+    out: String,
+    status: &'static str,
+    input_bytes: Option<u64>,
+    output_bytes: Option<u64>,
+    duration: Duration,
+    action: Option<&'static str>,
+    cache: Option<&'static str>,
+    quality: Option<u8>,
+    formats: Vec<FormatOutput>,
+    error: Option<CompressError>,
+}
+
+impl CompressResult {
+    /// Result of an entry which was never started as its batch was cancelled.
+    fn cancelled(task: &CompressTask) -> Self {
+        CompressResult {
+            input: task.input.to_string(),
+            out: task.out.to_string(),
+            status: "cancelled",
+            input_bytes: None,
+            output_bytes: None,
+            duration: Duration::default(),
+            action: None,
+            cache: None,
+            quality: None,
+            formats: vec![],
+            error: None,
+        }
+    }
+
+    /// Result of an entry which was skipped as its output is up to date.
+    fn up_to_date(task: &CompressTask, start: Instant) -> Self {
+        CompressResult {
+            input: task.input.to_string(),
+            out: task.out.to_string(),
+            status: "done",
+            input_bytes: fs::metadata(&task.input).map(|meta| meta.len()).ok(),
+            output_bytes: fs::metadata(&task.out).map(|meta| meta.len()).ok(),
+            duration: start.elapsed(),
+            action: Some("upToDate"),
+            cache: None,
+            quality: None,
+            formats: task
+                .formats
+                .iter()
+                .map(|&format| {
+                    let out = task.format_path(format);
+                    let bytes = fs::metadata(&out).map(|meta| meta.len()).ok();
+                    FormatOutput { format, out, bytes }
+                })
+                .collect(),
+            error: None,
+        }
+    }
+
+    fn to_js_object<'a, C: Context<'a>>(&self, cx: &mut C) -> JsResult<'a, JsObject> {
+        let obj = cx.empty_object();
+
+        let input = cx.string(&self.input);
+        obj.set(cx, "in", input)?;
+        let out = cx.string(&self.out);
+        obj.set(cx, "out", out)?;
+        let status = cx.string(self.status);
+        obj.set(cx, "status", status)?;
+        let input_bytes = js_optional_number(cx, self.input_bytes);
+        obj.set(cx, "inputBytes", input_bytes)?;
+        let output_bytes = js_optional_number(cx, self.output_bytes);
+        obj.set(cx, "outputBytes", output_bytes)?;
+        let saved_bytes: Handle<JsValue> = match (self.input_bytes, self.output_bytes) {
+            (Some(input), Some(output)) => cx.number(input as f64 - output as f64).upcast(),
+            _ => cx.null().upcast(),
+        };
+        obj.set(cx, "savedBytes", saved_bytes)?;
+        let duration_ms = cx.number(self.duration.as_secs_f64() * 1000.0);
+        obj.set(cx, "durationMs", duration_ms)?;
+        let action: Handle<JsValue> = match self.action {
+            Some(action) => cx.string(action).upcast(),
+            None => cx.null().upcast(),
+        };
+        obj.set(cx, "action", action)?;
+        let cache: Handle<JsValue> = match self.cache {
+            Some(cache) => cx.string(cache).upcast(),
+            None => cx.null().upcast(),
+        };
+        obj.set(cx, "cache", cache)?;
+        let quality = js_optional_number(cx, self.quality.map(u64::from));
+        obj.set(cx, "quality", quality)?;
+        let formats: Handle<JsValue> = if self.formats.is_empty() {
+            cx.null().upcast()
+        } else {
+            let formats = cx.empty_object();
+            set_format_output(cx, formats, "png", &self.out, self.output_bytes)?;
+            for output in &self.formats {
+                let name = output.format.name();
+                set_format_output(cx, formats, name, &output.out, output.bytes)?;
+            }
+            formats.upcast()
+        };
+        obj.set(cx, "formats", formats)?;
+        let error: Handle<JsValue> = match &self.error {
+            Some(err) => err.to_js_error(cx)?.upcast(),
+            None => cx.null().upcast(),
+        };
+        obj.set(cx, "error", error)?;
+
+        Ok(obj)
+    }
+}
+
+/// Sets the path and size of the output in the format `name` on `formats`.
+fn set_format_output<'a, C: Context<'a>>(
+    cx: &mut C,
+    formats: Handle<'a, JsObject>,
+    name: &str,
+    out: &str,
+    bytes: Option<u64>,
+) -> NeonResult<()> {
+    let obj = cx.empty_object();
+    let out = cx.string(out);
+    obj.set(cx, "out", out)?;
+    let bytes = js_optional_number(cx, bytes);
+    obj.set(cx, "bytes", bytes)?;
+    formats.set(cx, name, obj)?;
+    Ok(())
+}
+
+fn js_optional_number<'a, C: Context<'a>>(cx: &mut C, value: Option<u64>) -> Handle<'a, JsValue> {
+    match value {
+        Some(value) => cx.number(value as f64).upcast(),
+        None => cx.null().upcast(),
+    }
+}
+
+/// Converts the results of a batch into a JS array, keeping the order of the
+/// tasks it was created from.
+fn create_result_array<'a, C: Context<'a>>(
+    cx: &mut C,
+    results: &[CompressResult],
+) -> JsResult<'a, JsArray> {
+    let arr = JsArray::new(cx, results.len() as u32);
+    for (idx, result) in results.iter().enumerate() {
+        let obj = result.to_js_object(cx)?;
+        arr.set(cx, idx as u32, obj)?;
+    }
+    Ok(arr)
+}
+
+/// Converts the results of a batch into the value handed back to JS. That is
+/// the array of results, or in `dryRun` mode an object of the following
+/// structure with the projected savings of the whole batch.
+///
+/// {
+///  results: CompressResult[],
+///  inputBytes: number,
+///  outputBytes: number,
+///  savedBytes: number
+/// }
+///
+/// Only the entries with the "done" status are counted.
+fn create_batch_output<'a, C: Context<'a>>(
+    cx: &mut C,
+    results: &[CompressResult],
+    dry_run: bool,
+) -> JsResult<'a, JsValue> {
+    let arr = create_result_array(cx, results)?;
+    if !dry_run {
+        return Ok(arr.upcast());
+    }
+    let (input_bytes, output_bytes) = results
+        .iter()
+        .filter(|result| result.status == "done")
+        .fold((0, 0), |(input, output), result| {
+            (
+                input + result.input_bytes.unwrap_or(0),
+                output + result.output_bytes.unwrap_or(0),
+            )
+        });
+
+    let obj = cx.empty_object();
+    obj.set(cx, "results", arr)?;
+    let input = cx.number(input_bytes as f64);
+    obj.set(cx, "inputBytes", input)?;
+    let output = cx.number(output_bytes as f64);
+    obj.set(cx, "outputBytes", output)?;
+    let saved = cx.number(input_bytes as f64 - output_bytes as f64);
+    obj.set(cx, "savedBytes", saved)?;
+    Ok(obj.upcast())
+}
+
+/// Runs oxipng on the png `data`, followed by the zopfli pass when `zopfli`
+/// iterations are given and the `timeout` of the `options` is not used up yet.
+/// The zopfli pass can not be interrupted once it started.
+fn optimize_png(
+    data: &[u8],
+    options: &oxipng::Options,
+    zopfli: Option<NonZeroU64>,
+) -> Result<Vec<u8>, CompressError> {
+    let start = Instant::now();
+    let optimized = oxipng::optimize_from_memory(data, options)?;
+    let iterations = match zopfli {
+        Some(iterations) => iterations,
+        None => return Ok(optimized),
+    };
+    let deadline = options.timeout.map(|timeout| start + timeout);
+    Ok(deflate::recompress(&optimized, iterations, deadline).unwrap_or(optimized))
+}
+
+/// Image produced by `optimize_image`.
+struct Optimized<'a> {
+    /// The image to write: the optimised one, or the input when the savings
+    /// do not meet `minSavings`.
+    data: Cow<'a, [u8]>,
+    /// Whether `data` is the optimised image.
+    optimized: bool,
+    /// Quality of the optimised image when it was quantised in `lossy` mode.
+    quality: Option<u8>,
+    /// Whether the optimised image was found in the cache, if there is one.
+    cache: Option<&'static str>,
+}
+
+/// Runs the in-memory part of the pipeline of `task` on the png `input`, which
+/// the file and the buffer APIs share. With `resize` the image is resampled
+/// first, and the resampled image takes the place of the input from then on.
+/// It is optimised through the `cache`, and in `lossy` mode the quantised image
+/// as well, keeping it only if it ends up smaller. The result falls back to the
+/// input when it does not meet `minSavings`, and is checked with `verify` if
+/// asked to.
+fn optimize_image<'a>(
+    input: &'a [u8],
+    task: &CompressTask,
+    cache: Option<&Cache>,
+    dry_run: bool,
+) -> Result<Optimized<'a>, CompressError> {
+    let data = match task.resize {
+        Some(resize) => resize::resize(input, &resize)?.map_or(Cow::Borrowed(input), Cow::Owned),
+        None => Cow::Borrowed(input),
+    };
+    let (mut optimized, mut cached) = optimize_cached(&data, task, cache, dry_run)?;
+    let mut quality = None;
+    let quantized = task
+        .lossy
+        .and_then(|lossy| quantize::quantize(&data, &lossy));
+    if let Some(quantized) = &quantized {
+        let (lossy, lossy_cached) = optimize_cached(&quantized.data, task, cache, dry_run)?;
+        // Quantising smooth images can take more bytes than the lossless pass,
+        // so the smaller result wins, like with `pngquant --skip-if-larger`.
+        if lossy.len() < optimized.len() {
+            optimized = lossy;
+            cached = lossy_cached;
+            quality = Some(quantized.quality);
+        }
+    }
+    if let Some(min_savings) = task.min_savings {
+        if !min_savings.is_met(data.len() as u64, optimized.len() as u64) {
+            return Ok(Optimized {
+                data,
+                optimized: false,
+                quality: None,
+                cache: cached,
+            });
+        }
+    }
+    // `verify` is never combined with `lossy`, see `check_verify`.
+    if task.verify {
+        verify::verify(&data, &optimized)?;
+    }
+    Ok(Optimized {
+        data: Cow::Owned(optimized),
+        optimized: true,
+        quality,
+        cache: cached,
+    })
+}
+
+/// Optimises the png `data` of a buffer API call with the `options`, which
+/// leave out the file only ones, see `optimize_image`.
+fn optimize_data(data: &[u8], options: &CompressOptions) -> Result<Vec<u8>, CompressError> {
+    let task = CompressTask::new(String::new(), String::new(), options);
+    optimize_image(data, &task, None, false).map(|image| image.data.into_owned())
+}
+
+/// Optimises the png `data` of `task`, taking the result from `cache` when it
+/// holds it already. Returns the optimised image and whether the cache was hit.
+/// A `dry_run` reads the cache but does not store the result in it.
+fn optimize_cached(
+    data: &[u8],
+    task: &CompressTask,
+    cache: Option<&Cache>,
+    dry_run: bool,
+) -> Result<(Vec<u8>, Option<&'static str>), CompressError> {
+    let cache = match cache {
+        Some(cache) => cache,
+        None => return Ok((optimize_png(data, &task.options, task.zopfli)?, None)),
+    };
+    let key = Cache::key(data, &task.options, task.zopfli);
+    if let Some(optimized) = cache.get(&key) {
+        return Ok((optimized, Some("hit")));
+    }
+    let optimized = optimize_png(data, &task.options, task.zopfli)?;
+    if !dry_run {
+        cache.put(&key, &optimized);
+    }
+    Ok((optimized, Some("miss")))
+}
+
+/// Writes the `image` to the output of `task`, returning the size of the
+/// output file and the action which was taken. With `dry_run` nothing is
+/// written and the projected size and action are returned.
+fn write_optimized(
+    image: &Optimized,
+    task: &CompressTask,
+    dry_run: bool,
+) -> Result<(u64, &'static str), CompressError> {
+    let mtime = output_mtime(task)?;
+    let size = image.data.len() as u64;
+    let action = if image.optimized {
+        "optimized"
+    } else {
+        // Leave the output alone when it already holds the input, so its
+        // mtime does not change for nothing.
+        if fs::read(&task.out).is_ok_and(|existing| existing == *image.data) {
+            return Ok((size, "skipped"));
+        }
+        "copied"
+    };
+    if !dry_run {
+        write_output(task, &image.data, mtime)?;
+    }
+    Ok((size, action))
+}
+
+/// Encodes the `png` written for `task` in every extra format and writes the
+/// results beside it, unless `dry_run`.
+fn write_formats(
+    png: &[u8],
+    task: &CompressTask,
+    dry_run: bool,
+) -> Result<Vec<FormatOutput>, CompressError> {
+    if task.formats.is_empty() {
+        return Ok(vec![]);
+    }
+    let mtime = output_mtime(task)?;
+    task.formats
+        .iter()
+        .map(|&format| {
+            let data = match format {
+                Format::Webp => formats::webp(png, &task.webp)?,
+                Format::Avif => formats::avif(png, &task.avif)?,
+            };
+            let out = task.format_path(format);
+            if !dry_run {
+                output::write(&out, &data, mtime).map_err(|err| CompressError::write(&out, err))?;
+            }
+            Ok(FormatOutput {
+                format,
+                out,
+                bytes: Some(data.len() as u64),
+            })
+        })
+        .collect()
+}
+
+/// Modification time to give the outputs of `task`, the one of its input with
+/// `preserve_mtime`.
+fn output_mtime(task: &CompressTask) -> Result<Option<SystemTime>, CompressError> {
+    if !task.preserve_mtime {
+        return Ok(None);
+    }
+    let mtime = fs::metadata(&task.input).and_then(|meta| meta.modified());
+    mtime
+        .map(Some)
+        .map_err(|err| CompressError::read(&task.input, err))
+}
+
+fn write_output(
+    task: &CompressTask,
+    data: &[u8],
+    mtime: Option<SystemTime>,
+) -> Result<(), CompressError> {
+    if task.create_dirs && !task.in_place {
+        output::create_parent(&task.out)
+            .map_err(|(dir, err)| CompressError::create_dir(&dir, err))?;
+    }
+    let written = if task.in_place {
+        output::replace(&task.out, data, mtime)
+    } else {
+        output::write(&task.out, data, mtime)
+    };
+    written.map_err(|err| CompressError::write(&task.out, err))
+}
+
+/// Perform png image compression using oxipng. It takes a `CompressTask` with
+/// the input file name, the output filename and the options to use, and
+/// executes the oxipng compression logic. Returns a `CompressResult` describing
+/// how the compression went.
 ///
 /// # Arguments
 ///
-/// * `inputfile` - String input png filename
-/// * `outputfile` - String output png filename
+/// * `task` - The `CompressTask` to perform
+/// * `context` - State shared by the entries of the batch
 ///
 /// # Examples
 ///
 /// ```
-/// perform("./website/static/img/demo.png", "./dist/static/demo.png")
+/// let task = CompressTask::new(
+///     "./website/static/img/demo.png".to_string(),
+///     "./dist/static/demo.png".to_string(),
+///     &options,
+/// );
+/// perform(&task, &BatchContext::default())
 /// ```
-fn perform(inputfile: String, outputfile: String) -> String {
-    let mut options = oxipng::Options::from_preset(5);
-    options.timeout = Some(Duration::from_secs(2));
-    let infile = oxipng::InFile::Path(PathBuf::from(inputfile));
-    let outfile = oxipng::OutFile::Path(Some(PathBuf::from(outputfile)));
-    match oxipng::optimize(&infile, &outfile, &options) {
-        Ok(_) => String::from("done"),
-        Err(_) => String::from("error"),
+fn perform(task: &CompressTask, context: &BatchContext) -> CompressResult {
+    let start = Instant::now();
+    if task.incremental {
+        let up_to_date = match &context.manifest {
+            Some(manifest) => manifest.is_up_to_date(task),
+            None => manifest::is_newer(&task.input, &task.out),
+        };
+        // Outputs in the extra formats are not recorded in the manifest.
+        let formats_up_to_date = task
+            .formats
+            .iter()
+            .all(|&format| manifest::is_newer(&task.input, &task.format_path(format)));
+        if up_to_date && formats_up_to_date {
+            return CompressResult::up_to_date(task, start);
+        }
+    }
+
+    let (input_bytes, outcome) = match fs::read(&task.input) {
+        Ok(input) => {
+            let outcome = optimize_image(&input, task, context.cache.as_deref(), context.dry_run)
+                .inspect_err(|err| {
+                    // Checked before writing, so an in place entry never loses
+                    // its original. Do not leave the output of an earlier run
+                    // behind either.
+                    if err.code == "ERR_VERIFY_FAILED" && !task.in_place && !context.dry_run {
+                        let _ = fs::remove_file(&task.out);
+                    }
+                })
+                .and_then(|image| {
+                    let (output_bytes, action) = write_optimized(&image, task, context.dry_run)?;
+                    let formats = write_formats(&image.data, task, context.dry_run)?;
+                    Ok((output_bytes, action, image.cache, image.quality, formats))
+                });
+            (Some(input.len() as u64), outcome)
+        }
+        Err(err) => (None, Err(CompressError::read(&task.input, err))),
+    };
+
+    let (status, output_bytes, action, cache, quality, formats, error) = match outcome {
+        Ok((output_bytes, action, cache, quality, formats)) => (
+            "done",
+            Some(output_bytes),
+            Some(action),
+            cache,
+            quality,
+            formats,
+            None,
+        ),
+        Err(err) => ("error", None, None, None, None, vec![], Some(err)),
+    };
+    if let (Some(manifest), "done", false) = (&context.manifest, status, context.dry_run) {
+        manifest.record(task);
+    }
+    CompressResult {
+        input: task.input.to_string(),
+        out: task.out.to_string(),
+        status,
+        input_bytes,
+        output_bytes,
+        duration: start.elapsed(),
+        action,
+        cache,
+        quality,
+        formats,
+        error,
     }
 }
 
-/// Compress function to compress `png` files using onxipng.
-/// It spawns up some amount of threads, (configurable via PNG_COMPRESS_THREADS env
-/// variable), default value is 8. Them it basically chunked the complete array
-/// which is sent for processing having the following structure.
+/// Compresses `png` files with oxipng, blocking until the whole batch is done.
+/// It takes an array of entries of the following structure, with the optional
+/// `options` overriding the `BatchOptions` passed as the second argument.
 ///
 /// {
 ///  in: "string",
-///  out: "string"
+///  out: "string",
+///  options: object | undefined
 /// }
 ///
-/// These entries will create `CompressTask` object which will be delegated to oxinpng
-/// for handling the compression.
+/// Every entry becomes a `CompressTask`, and the tasks are put on a queue which
+/// is shared by the worker threads, each taking the next entry as soon as it is
+/// done with its last one. The number of threads is the `concurrency` option,
+/// else the `PNG_COMPRESS_THREADS` environment variable, else the available
+/// parallelism of the machine, and never more than there are entries.
 ///
-/// It iterates on the array which is sent from the calle function to this as an argument.
+/// Returns an array with a `CompressResult` for every entry, in the order the
+/// entries were passed, or in `dryRun` mode the object built by
+/// `create_batch_output`. Throws if the batch fails as a whole, such as on the
+/// first error in `failFast` mode. Throws a `TypeError` when given a `signal`,
+/// which could never fire while the batch blocks the event loop.
 ///
 /// # Arguments
 ///
 /// * `cx` - Function context created by neon binding
-fn compress(mut cx: FunctionContext) -> JsResult<JsNumber> {
-    let js_arr_handle: Handle<JsArray> = cx.argument(0)?;
-    let mut vec: Vec<Handle<JsValue>> = js_arr_handle.to_vec(&mut cx)?;
-    let compress_arr = {
-        let arr = vec
-            .iter_mut()
-            .map(|val| create_compress_task(val, &mut cx))
-            .collect::<Vec<CompressTask>>();
-
-        Arc::new(arr)
+fn compress(mut cx: FunctionContext) -> JsResult<JsValue> {
+    let js_arr_handle = array_argument(&mut cx, "an array of tasks")?;
+    let options = create_batch_options(&mut cx)?;
+    let value = cx.argument_opt(1);
+    if options::has_signal(&mut cx, value)? {
+        return cx.throw_type_error(
+            "Option `signal` is not supported by compress, which blocks the event loop, use compressAsync",
+        );
+    }
+    let compress_arr = create_compress_tasks(js_arr_handle, &options, &mut cx)?;
+
+    match run_batch(compress_arr, &options) {
+        Ok(results) => create_batch_output(&mut cx, &results, options.dry_run),
+        Err(err) => {
+            let js_err = err.to_js_error(&mut cx)?;
+            cx.throw(js_err)
+        }
+    }
+}
+
+/// Asynchronous variant of `compress` which never blocks the Node event loop.
+/// The task array is read on the main thread, after which the whole batch is
+/// handed over to a background thread. The returned promise resolves with the
+/// same array of results as `compress` once every worker thread has finished, or
+/// rejects with the error which made the batch fail. Once the `signal` of the
+/// batch is aborted no more entries are started, the entries which are already
+/// being compressed are finished and the rest is reported as `cancelled`.
+///
+/// # Arguments
+///
+/// * `cx` - Function context created by neon binding
+fn compress_async(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let js_arr_handle = array_argument(&mut cx, "an array of tasks")?;
+    let mut options = create_batch_options(&mut cx)?;
+    let compress_arr = create_compress_tasks(js_arr_handle, &options, &mut cx)?;
+    watch_signal(&mut cx, &mut options)?;
+    let signal = options.signal.take();
+
+    let channel = cx.channel();
+    let (deferred, promise) = cx.promise();
+    thread::spawn(move || {
+        let result = run_batch(compress_arr, &options);
+        settle_batch(deferred, &channel, signal, result, options.dry_run);
+    });
+
+    Ok(promise)
+}
+
+/// Compresses every png of a source tree. It takes an object with the `src`
+/// directory to walk, the `dest` directory its relative structure is mirrored
+/// into, optional `include` and `exclude` globs matched against the relative
+/// paths and a `gitignore` toggle, see `DirOptions`. The second argument takes
+/// the same `BatchOptions` as `compress`, where `inPlace` replaces the files in
+/// `src` and takes the place of `dest`.
+///
+/// Both the walk and the compression run on a background thread. The returned
+/// promise resolves with the same array of results as `compressAsync`, with one
+/// entry per file found, or rejects if the tree could not be walked.
+///
+/// # Arguments
+///
+/// * `cx` - Function context created by neon binding
+fn compress_dir(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let value = cx.argument_opt(0);
+    let dir = options::parse_dir_options(&mut cx, value)?;
+    let mut options = create_batch_options(&mut cx)?;
+    match (&dir.dest, options.compress.in_place.unwrap_or(false)) {
+        (None, false) => return cx.throw_type_error("Field `dest` must be a non-empty string"),
+        (Some(_), true) => {
+            return cx.throw_type_error("Field `dest` can not be combined with `inPlace`")
+        }
+        _ => {}
+    }
+    if let Some(message) =
+        check_incremental(&options.compress, &options).or_else(|| check_verify(&options.compress))
+    {
+        return cx.throw_type_error(message);
+    }
+    watch_signal(&mut cx, &mut options)?;
+    let signal = options.signal.take();
+
+    let channel = cx.channel();
+    let (deferred, promise) = cx.promise();
+    thread::spawn(move || {
+        let result = walk::collect_files(&dir).and_then(|files| {
+            let compress_arr = files
+                .into_iter()
+                .map(|(input, out)| CompressTask::new(input, out, &options.compress))
+                .collect::<Vec<CompressTask>>();
+            run_batch(Arc::new(compress_arr), &options)
+        });
+        settle_batch(deferred, &channel, signal, result, options.dry_run);
+    });
+
+    Ok(promise)
+}
+
+/// Settles the promise of a batch which ran on a background thread, after
+/// removing its `signal` listener.
+fn settle_batch(
+    deferred: Deferred,
+    channel: &Channel,
+    signal: Option<AbortListener>,
+    result: Result<Vec<CompressResult>, CompressError>,
+    dry_run: bool,
+) {
+    deferred.settle_with(channel, move |mut cx| {
+        if let Some(signal) = signal {
+            signal.remove(&mut cx)?;
+        }
+        match result {
+            Ok(results) => create_batch_output(&mut cx, &results, dry_run),
+            Err(err) => {
+                let js_err = err.to_js_error(&mut cx)?;
+                cx.throw(js_err)
+            }
+        }
+    });
+}
+
+/// Compresses a png image held in memory. It takes a Node `Buffer` (or any
+/// `Uint8Array`) and an optional options object, the same as the `options` of a
+/// `compress` entry. The compression runs on a background thread and the
+/// returned promise resolves with a new `Buffer` holding the optimised png, or
+/// rejects with the `CompressError` which made it fail.
+///
+/// # Arguments
+///
+/// * `cx` - Function context created by neon binding
+fn compress_buffer(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let js_buffer: Handle<JsValue> = cx.argument(0)?;
+    let data = match read_buffer(js_buffer, &mut cx)? {
+        Some(data) => data,
+        None => return cx.throw_type_error("Expected a Buffer or Uint8Array"),
     };
+    let options = create_buffer_options(&mut cx)?;
+    if let Some(key) = file_only_option(&options) {
+        return cx.throw_type_error(format!(
+            "Option `{}` is not supported by compressBuffer",
+            key
+        ));
+    }
+    if let Some(message) = check_verify(&options) {
+        return cx.throw_type_error(message);
+    }
+    let channel = cx.channel();
+    let (deferred, promise) = cx.promise();
+    thread::spawn(move || {
+        let result = optimize_data(&data, &options);
+        deferred.settle_with(&channel, move |mut cx| match result {
+            Ok(optimized) => create_buffer(&mut cx, &optimized),
+            Err(err) => {
+                let js_err = err.to_js_error(&mut cx)?;
+                cx.throw(js_err)
+            }
+        });
+    });
 
-    let mut handles = vec![];
-    // Check for the env variable `PNG_COMPRESS_THREADS` for the value or fallback to the default
-    // value of 8. If the passed array size is less than the no of threads set or even the default
-    // fallback them use the array length to evenly distribute the load. In case of an invalid
-    // value set run it in the single thread.
-    let num_threads: usize = option_env!("PNG_COMPRESS_THREADS")
-        .unwrap_or("8")
-        .to_string()
-        .parse::<usize>()
-        .unwrap_or(1)
-        .max(compress_arr.len());
-
-    let chunk_size = (compress_arr.len() as f64 / num_threads as f64).ceil() as usize;
-    for idx in 0..num_threads {
-        let data_clone = compress_arr.clone();
-        let th = thread::spawn(move || {
-            data_clone
-                .chunks(chunk_size)
-                .nth(idx)
-                .unwrap()
-                .iter()
-                .for_each(|item| {
-                    let input = item.input.to_string();
-                    let out = item.out.to_string();
-                    perform(input, out);
+    Ok(promise)
+}
+
+/// Batch variant of `compressBuffer` which takes an array of buffers and spreads
+/// them over the worker threads the same way as `compress`, taking the same
+/// `BatchOptions`. The returned promise resolves with an array of optimised
+/// buffers in the same order, or rejects with the error of the first buffer
+/// which failed. Aborting the `signal` rejects the promise with `ERR_CANCELLED`.
+///
+/// # Arguments
+///
+/// * `cx` - Function context created by neon binding
+fn compress_buffers(mut cx: FunctionContext) -> JsResult<JsPromise> {
+    let js_arr_handle = array_argument(&mut cx, "an array of buffers")?;
+    let mut buffers = vec![];
+    for (idx, val) in js_arr_handle.to_vec(&mut cx)?.into_iter().enumerate() {
+        match read_buffer(val, &mut cx)? {
+            Some(data) => buffers.push(data),
+            None => {
+                return cx.throw_type_error(format!(
+                    "Entry at index {} must be a Buffer or Uint8Array",
+                    idx
+                ))
+            }
+        }
+    }
+    let mut options = create_batch_options(&mut cx)?;
+    if options.progress.is_some() {
+        return cx.throw_type_error("Option `onProgress` is not supported by compressBuffers");
+    }
+    if options.cache.is_some() {
+        return cx.throw_type_error("Option `cacheDir` is not supported by compressBuffers");
+    }
+    if options.manifest.is_some() {
+        return cx.throw_type_error("Option `manifest` is not supported by compressBuffers");
+    }
+    if options.dry_run {
+        return cx.throw_type_error("Option `dryRun` is not supported by compressBuffers");
+    }
+    if let Some(key) = file_only_option(&options.compress) {
+        return cx.throw_type_error(format!(
+            "Option `{}` is not supported by compressBuffers",
+            key
+        ));
+    }
+    if let Some(message) = check_verify(&options.compress) {
+        return cx.throw_type_error(message);
+    }
+    watch_signal(&mut cx, &mut options)?;
+    let concurrency = options.concurrency;
+    let largest_first = options.largest_first;
+    let compress_options = options.compress.clone();
+    let signal = options.signal.take();
+
+    let channel = cx.channel();
+    let (deferred, promise) = cx.promise();
+    thread::spawn(move || {
+        // A single failure rejects the whole batch, so there is no point in
+        // optimising the remaining buffers after it.
+        let aborted = options.cancelled.clone();
+        let job_aborted = aborted.clone();
+        let order = pool::schedule(
+            buffers.iter().map(|data| data.len() as u64).collect(),
+            largest_first,
+        );
+        let result = pool::run_parallel(
+            Arc::new(buffers),
+            order,
+            concurrency,
+            aborted,
+            move |data| {
+                let result = optimize_data(data, &compress_options);
+                if result.is_err() {
+                    job_aborted.store(true, Ordering::SeqCst);
+                }
+                result
+            },
+        )
+        .and_then(|results| {
+            if let Some(Err(err)) = results.iter().flatten().find(|result| result.is_err()) {
+                return Err(err.clone());
+            }
+            results
+                .into_iter()
+                .map(|result| match result {
+                    Some(result) => result,
+                    None => Err(CompressError::new(
+                        "ERR_CANCELLED",
+                        "The batch was cancelled",
+                    )),
                 })
+                .collect::<Result<Vec<_>, _>>()
+        });
+
+        deferred.settle_with(&channel, move |mut cx| {
+            if let Some(signal) = signal {
+                signal.remove(&mut cx)?;
+            }
+            match result {
+                Ok(buffers) => {
+                    let arr = JsArray::new(&mut cx, buffers.len() as u32);
+                    for (idx, optimized) in buffers.iter().enumerate() {
+                        let buffer = create_buffer(&mut cx, optimized)?;
+                        arr.set(&mut cx, idx as u32, buffer)?;
+                    }
+                    Ok(arr)
+                }
+                Err(err) => {
+                    let js_err = err.to_js_error(&mut cx)?;
+                    cx.throw(js_err)
+                }
+            }
         });
-        handles.push(th);
+    });
+
+    Ok(promise)
+}
+
+/// Copies the bytes of a `Buffer` or `Uint8Array` so they can be moved to a
+/// worker thread. Returns `None` for any other value.
+fn read_buffer(val: Handle<JsValue>, cx: &mut FunctionContext) -> NeonResult<Option<Vec<u8>>> {
+    let buffer = match val.downcast::<JsTypedArray<u8>, _>(cx) {
+        Ok(buffer) => buffer,
+        Err(_) => return Ok(None),
+    };
+    // `as_slice` builds the slice from the raw data pointer, which is null for
+    // an empty buffer.
+    let length = buffer.get::<JsNumber, _, _>(cx, "length")?.value(cx);
+    if length == 0.0 {
+        return Ok(Some(vec![]));
+    }
+    Ok(Some(buffer.as_slice(cx).to_vec()))
+}
+
+/// Reads the array passed as the first argument, throwing a `TypeError` which
+/// describes the `expected` value otherwise.
+fn array_argument<'a>(cx: &mut FunctionContext<'a>, expected: &str) -> JsResult<'a, JsArray> {
+    match cx.argument_opt(0).map(|val| val.downcast::<JsArray, _>(cx)) {
+        Some(Ok(arr)) => Ok(arr),
+        _ => cx.throw_type_error(format!("Expected {} as the first argument", expected)),
+    }
+}
+
+fn create_buffer<'a, C: Context<'a>>(cx: &mut C, data: &[u8]) -> JsResult<'a, JsBuffer> {
+    let mut buffer = JsBuffer::new(cx, data.len())?;
+    buffer.as_mut_slice(cx).copy_from_slice(data);
+    Ok(buffer)
+}
+
+/// Returns the first option set in `options` which only applies to files.
+fn file_only_option(options: &CompressOptions) -> Option<&'static str> {
+    if options.in_place.is_some() {
+        Some("inPlace")
+    } else if options.preserve_mtime.is_some() {
+        Some("preserveMtime")
+    } else if options.create_dirs.is_some() {
+        Some("createDirs")
+    } else if options.incremental.is_some() {
+        Some("incremental")
+    } else if options.formats.is_some() {
+        Some("formats")
+    } else {
+        None
+    }
+}
+
+/// Reads the optional options object passed as the second argument of
+/// `compressBuffer`.
+fn create_buffer_options(cx: &mut FunctionContext) -> NeonResult<CompressOptions> {
+    let value = match cx.argument_opt(1) {
+        Some(value) => value,
+        None => cx.undefined().upcast(),
+    };
+    options::parse_compress_options(cx, value)
+}
+
+/// Reads the JS task array passed as an argument into a shared list of
+/// `CompressTask` entries which can be handed over to the worker threads.
+/// The compression options of the `batch` are merged with the options of every
+/// entry.
+fn create_compress_tasks<'a>(
+    js_arr_handle: Handle<'a, JsArray>,
+    batch: &BatchOptions,
+    cx: &mut FunctionContext<'a>,
+) -> NeonResult<Arc<Vec<CompressTask>>> {
+    let vec: Vec<Handle<'a, JsValue>> = js_arr_handle.to_vec(cx)?;
+    let arr = vec
+        .into_iter()
+        .enumerate()
+        .map(|(idx, val)| create_compress_task(idx, val, batch, cx))
+        .collect::<NeonResult<Vec<CompressTask>>>()?;
+
+    Ok(Arc::new(arr))
+}
+
+/// Reads the optional `BatchOptions` object passed as the second argument.
+fn create_batch_options(cx: &mut FunctionContext) -> NeonResult<BatchOptions> {
+    let value = cx.argument_opt(1);
+    options::parse_batch_options(cx, value)
+}
+
+/// Registers the `signal` of the batch options, to be called once every check
+/// of the entry point passed, see `options::watch_signal`.
+fn watch_signal(cx: &mut FunctionContext, options: &mut BatchOptions) -> NeonResult<()> {
+    let value = cx.argument_opt(1);
+    options::watch_signal(cx, value, options)
+}
+
+/// Runs the compression of every entry in `compress_arr` on the worker pool.
+/// Blocks the calling thread until all of the workers have finished and
+/// returns the results in the order of the entries, where the entries which
+/// were never started because the batch got cancelled are marked `cancelled`.
+/// Fails if any of the workers panicked or, when `failFast` is set, with the
+/// error of the first failing entry.
+fn run_batch(
+    compress_arr: Arc<Vec<CompressTask>>,
+    options: &BatchOptions,
+) -> Result<Vec<CompressResult>, CompressError> {
+    // Raised on cancellation, or by the first failing entry in `failFast` mode,
+    // so the workers stop picking up new entries.
+    let aborted = options.cancelled.clone();
+    let fail_fast = options.fail_fast;
+
+    let sizes = compress_arr
+        .iter()
+        .map(|task| {
+            fs::metadata(&task.input)
+                .map(|meta| meta.len())
+                .unwrap_or(0)
+        })
+        .collect();
+    let order = pool::schedule(sizes, options.largest_first);
+
+    let total = compress_arr.len();
+    let progress = options.progress.clone();
+    let context = Arc::new(BatchContext {
+        cache: options.cache.clone(),
+        manifest: match &options.manifest {
+            Some(path) => Some(Manifest::load(path.clone())?),
+            None => None,
+        },
+        dry_run: options.dry_run,
+    });
+    let job_context = context.clone();
+    let job_aborted = aborted.clone();
+    let results = pool::run_parallel(
+        compress_arr.clone(),
+        order,
+        options.concurrency,
+        aborted,
+        move |item| {
+            let result = perform(item, &job_context);
+            if fail_fast && result.error.is_some() {
+                job_aborted.store(true, Ordering::SeqCst);
+            }
+            if let Some(progress) = &progress {
+                progress.report(&result, total);
+            }
+            result
+        },
+    )?;
+    let results = results
+        .into_iter()
+        .zip(compress_arr.iter())
+        .map(|(result, task)| result.unwrap_or_else(|| CompressResult::cancelled(task)))
+        .collect::<Vec<CompressResult>>();
+    if let (Some(manifest), false) = (&context.manifest, context.dry_run) {
+        manifest.save()?;
     }
 
-    for handle in handles {
-        handle.join().unwrap();
+    if fail_fast {
+        if let Some(err) = results.iter().find_map(|result| result.error.clone()) {
+            return Err(err);
+        }
     }
-    Ok(cx.number(vec.len() as f64))
+    Ok(results)
 }
 
-fn create_compress_task(val: &mut Handle<JsValue>, cx: &mut CallContext<JsObject>) -> CompressTask {
-    let js_object = val
-        .downcast::<JsObject, FunctionContext>(cx)
-        .or_throw(cx)
-        .unwrap();
-    let infilename = js_object
-        .get(cx, "in")
-        .unwrap()
-        .downcast::<JsString, FunctionContext>(cx)
-        .or_throw(cx)
-        .unwrap()
-        .value(cx);
-    let outfilename = js_object
-        .get(cx, "out")
-        .unwrap()
-        .downcast::<JsString, FunctionContext>(cx)
-        .or_throw(cx)
-        .unwrap()
-        .value(cx);
-    CompressTask {
-        input: infilename,
-        out: outfilename,
+/// Validates the entry at `idx` of the task array and reads it into a
+/// `CompressTask`. Every problem is thrown as a `TypeError` (or `RangeError`)
+/// naming the index of the entry and the offending field.
+fn create_compress_task<'a>(
+    idx: usize,
+    val: Handle<'a, JsValue>,
+    batch: &BatchOptions,
+    cx: &mut FunctionContext<'a>,
+) -> NeonResult<CompressTask> {
+    let js_object = match val.downcast::<JsObject, _>(cx) {
+        Ok(js_object) => js_object,
+        Err(_) => return cx.throw_type_error(format!("Task at index {} must be an object", idx)),
+    };
+    if let Some(key) = options::find_unknown_key(cx, js_object, &[TASK_KEYS])? {
+        return cx.throw_type_error(format!("Task at index {}: unknown field `{}`", idx, key));
+    }
+    let task_options = js_object.get_value(cx, "options")?;
+    let task_options = match cx.try_catch(|cx| options::parse_compress_options(cx, task_options)) {
+        Ok(task_options) => task_options,
+        Err(err) => return rethrow_for_task(idx, err, cx),
+    };
+    let options = batch.compress.merge(&task_options);
+    let in_place = options.in_place.unwrap_or(false);
+
+    let infilename = read_task_path(idx, js_object, "in", cx)?;
+    // In place entries write back to `in`, so `out` can be left out.
+    let outfilename = if in_place && js_object.get_value(cx, "out")?.is_a::<JsUndefined, _>(cx) {
+        infilename.clone()
+    } else {
+        read_task_path(idx, js_object, "out", cx)?
+    };
+    if in_place && outfilename != infilename {
+        return cx.throw_type_error(format!(
+            "Task at index {}: field `out` must be left out or equal `in` with `inPlace`",
+            idx
+        ));
     }
+    if let Some(message) = check_incremental(&options, batch).or_else(|| check_verify(&options)) {
+        return cx.throw_type_error(format!("Task at index {}: {}", idx, message));
+    }
+    Ok(CompressTask::new(infilename, outfilename, &options))
 }
 
-register_module!(mut m, { m.export_function("compress", compress) });
+/// Rejects `verify` together with `lossy`, which changes the pixels on purpose.
+/// `resize` is exempt, the resampled image is what the output is checked
+/// against.
+fn check_verify(options: &CompressOptions) -> Option<&'static str> {
+    if options.verify.unwrap_or(false) && options.lossy.flatten().is_some() {
+        return Some("Option `verify` can not be combined with `lossy`");
+    }
+    None
+}
+
+/// Rejects `incremental` in place entries without a `manifest`, their output
+/// is always as new as their input.
+fn check_incremental(options: &CompressOptions, batch: &BatchOptions) -> Option<&'static str> {
+    let in_place = options.in_place.unwrap_or(false);
+    if in_place && options.incremental.unwrap_or(false) && batch.manifest.is_none() {
+        return Some("Option `incremental` needs a `manifest` with `inPlace`");
+    }
+    None
+}
+
+fn read_task_path<'a>(
+    idx: usize,
+    js_object: Handle<'a, JsObject>,
+    field: &str,
+    cx: &mut FunctionContext<'a>,
+) -> NeonResult<String> {
+    let value = js_object.get_value(cx, field)?;
+    match value.downcast::<JsString, _>(cx).map(|path| path.value(cx)) {
+        Ok(path) if !path.is_empty() => Ok(path),
+        _ => cx.throw_type_error(format!(
+            "Task at index {}: field `{}` must be a non-empty string",
+            idx, field
+        )),
+    }
+}
+
+/// Rethrows an error raised while reading the `options` of the entry at `idx`,
+/// with the index and field prefixed to its message.
+fn rethrow_for_task<'a, T>(
+    idx: usize,
+    err: Handle<'a, JsValue>,
+    cx: &mut FunctionContext<'a>,
+) -> NeonResult<T> {
+    if let Ok(js_err) = err.downcast::<JsError, _>(cx) {
+        let message = js_err.get::<JsString, _, _>(cx, "message")?.value(cx);
+        let message = cx.string(format!(
+            "Task at index {}: field `options`: {}",
+            idx, message
+        ));
+        js_err.set(cx, "message", message)?;
+    }
+    cx.throw(err)
+}
+
+#[neon::main]
+fn main(mut cx: ModuleContext) -> NeonResult<()> {
+    cx.export_function("compress", compress)?;
+    cx.export_function("compressAsync", compress_async)?;
+    cx.export_function("compressDir", compress_dir)?;
+    cx.export_function("compressBuffer", compress_buffer)?;
+    cx.export_function("compressBuffers", compress_buffers)?;
+    Ok(())
+}
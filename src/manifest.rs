@@ -0,0 +1,239 @@
+use crate::cache::hex_digest;
+use crate::error::CompressError;
+use crate::output;
+use crate::CompressTask;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::{self, Metadata};
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+/// Bumped whenever the format of the records changes, so older manifests are
+/// discarded instead of misread.
+const MANIFEST_VERSION: u32 = 1;
+
+/// What an output file was produced from, recorded when it was written.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Record {
+    input: String,
+    input_bytes: u64,
+    input_mtime: u64,
+    options: String,
+    output_bytes: u64,
+    output_mtime: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ManifestFile {
+    version: u32,
+    outputs: HashMap<String, Record>,
+}
+
+/// Manifest of the `incremental` mode, stored as a JSON file mapping every
+/// output path to the size, modification time and options of the input it was
+/// produced from. An entry is up to date when its record still matches both
+/// the input and the output on disk.
+pub struct Manifest {
+    path: PathBuf,
+    records: Mutex<HashMap<String, Record>>,
+}
+
+impl Manifest {
+    /// Loads the manifest at `path`. A missing, unreadable as JSON or outdated
+    /// manifest starts out empty, so every entry is compressed again.
+    pub fn load(path: PathBuf) -> Result<Self, CompressError> {
+        let records = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice::<ManifestFile>(&data)
+                .ok()
+                .filter(|file| file.version == MANIFEST_VERSION)
+                .map(|file| file.outputs)
+                .unwrap_or_default(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(err) => return Err(CompressError::read(&path.display().to_string(), err)),
+        };
+        Ok(Manifest {
+            path,
+            records: Mutex::new(records),
+        })
+    }
+
+    /// Whether the output of `task` was produced from its input as it is now,
+    /// with the same options, and was not touched since.
+    pub fn is_up_to_date(&self, task: &CompressTask) -> bool {
+        let records = self.records.lock().unwrap_or_else(|err| err.into_inner());
+        match (records.get(&task.out), current_record(task)) {
+            (Some(recorded), Some(current)) => *recorded == current,
+            _ => false,
+        }
+    }
+
+    /// Records the output of `task`, which was just written.
+    pub fn record(&self, task: &CompressTask) {
+        if let Some(record) = current_record(task) {
+            let mut records = self.records.lock().unwrap_or_else(|err| err.into_inner());
+            records.insert(task.out.to_string(), record);
+        }
+    }
+
+    /// Writes the manifest back to its file.
+    pub fn save(&self) -> Result<(), CompressError> {
+        let records = self.records.lock().unwrap_or_else(|err| err.into_inner());
+        let file = ManifestFile {
+            version: MANIFEST_VERSION,
+            outputs: records.clone(),
+        };
+        let path = self.path.display().to_string();
+        let data = serde_json::to_vec_pretty(&file)
+            .map_err(|err| CompressError::write(&path, io::Error::other(err)))?;
+        output::create_parent(&path).map_err(|(dir, err)| CompressError::create_dir(&dir, err))?;
+        output::write_atomic(&self.path, &data, None, None)
+            .map_err(|err| CompressError::write(&path, err))
+    }
+}
+
+/// Key of the options which change the output of a task.
+fn options_key(task: &CompressTask) -> String {
+    let options = format!(
+        "{:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?}",
+        task.options,
+        task.min_savings,
+        task.lossy,
+        task.zopfli,
+        task.formats,
+        task.webp,
+        task.avif,
+        task.resize
+    );
+    hex_digest(&[options.as_bytes()])
+}
+
+/// Whether the output `out` is at least as new as its `input`, the same check
+/// `make` does.
+pub fn is_newer(input: &str, out: &str) -> bool {
+    match (fs::metadata(input), fs::metadata(out)) {
+        (Ok(input), Ok(out)) => match (input.modified(), out.modified()) {
+            (Ok(input), Ok(out)) => out >= input,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+fn current_record(task: &CompressTask) -> Option<Record> {
+    let input = fs::metadata(&task.input).ok()?;
+    let out = fs::metadata(&task.out).ok()?;
+    Some(Record {
+        input: task.input.to_string(),
+        input_bytes: input.len(),
+        input_mtime: mtime_nanos(&input)?,
+        options: options_key(task),
+        output_bytes: out.len(),
+        output_mtime: mtime_nanos(&out)?,
+    })
+}
+
+fn mtime_nanos(meta: &Metadata) -> Option<u64> {
+    let mtime = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    u64::try_from(mtime.as_nanos()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::options::CompressOptions;
+    use crate::test_dir::TestDir;
+
+    fn new_task(dir: &TestDir, options: &CompressOptions) -> CompressTask {
+        CompressTask::new(dir.join("in.png"), dir.join("out.png"), options)
+    }
+
+    fn written(name: &str) -> (TestDir, CompressTask) {
+        let dir = TestDir::new(name);
+        dir.write("in.png", b"input");
+        dir.write("out.png", b"out");
+        let task = new_task(&dir, &CompressOptions::default());
+        (dir, task)
+    }
+
+    #[test]
+    fn unrecorded_output_is_outdated() {
+        let (dir, task) = written("manifest-missing");
+        let manifest = Manifest::load(dir.path().join("manifest.json")).unwrap();
+        assert!(!manifest.is_up_to_date(&task));
+    }
+
+    #[test]
+    fn recorded_output_is_up_to_date() {
+        let (dir, task) = written("manifest-record");
+        let manifest = Manifest::load(dir.path().join("manifest.json")).unwrap();
+        manifest.record(&task);
+        assert!(manifest.is_up_to_date(&task));
+
+        let options = CompressOptions {
+            level: Some(1),
+            ..Default::default()
+        };
+        assert!(!manifest.is_up_to_date(&new_task(&dir, &options)));
+    }
+
+    #[test]
+    fn changed_files_are_outdated() {
+        let (dir, task) = written("manifest-changed");
+        let manifest = Manifest::load(dir.path().join("manifest.json")).unwrap();
+        manifest.record(&task);
+        dir.write("out.png", b"edited");
+        assert!(!manifest.is_up_to_date(&task));
+
+        manifest.record(&task);
+        dir.write("in.png", b"new input");
+        assert!(!manifest.is_up_to_date(&task));
+
+        fs::remove_file(&task.input).unwrap();
+        assert!(!manifest.is_up_to_date(&task));
+    }
+
+    #[test]
+    fn saved_records_are_loaded() {
+        let (dir, task) = written("manifest-save");
+        let path = dir.path().join("cache/manifest.json");
+        let manifest = Manifest::load(path.clone()).unwrap();
+        manifest.record(&task);
+        manifest.save().unwrap();
+        assert!(Manifest::load(path).unwrap().is_up_to_date(&task));
+    }
+
+    #[test]
+    fn other_versions_are_discarded() {
+        let (dir, task) = written("manifest-version");
+        let path = dir.path().join("manifest.json");
+        let manifest = Manifest::load(path.clone()).unwrap();
+        manifest.record(&task);
+        manifest.save().unwrap();
+        let data = fs::read_to_string(&path).unwrap();
+        let data = data.replace(
+            &format!("\"version\": {}", MANIFEST_VERSION),
+            &format!("\"version\": {}", MANIFEST_VERSION + 1),
+        );
+        fs::write(&path, data).unwrap();
+        assert!(!Manifest::load(path).unwrap().is_up_to_date(&task));
+    }
+
+    #[test]
+    fn invalid_json_starts_empty() {
+        let (dir, task) = written("manifest-invalid");
+        let path = dir.write("manifest.json", b"{\"version\": 1, \"outputs\": [");
+        let manifest = Manifest::load(PathBuf::from(path)).unwrap();
+        assert!(!manifest.is_up_to_date(&task));
+    }
+
+    #[test]
+    fn unreadable_manifest_fails() {
+        let dir = TestDir::new("manifest-unreadable");
+        let err = Manifest::load(dir.path().to_path_buf()).err().unwrap();
+        assert_eq!(err.code, "ERR_READ_FAILED");
+    }
+}
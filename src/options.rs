@@ -0,0 +1,1393 @@
+use crate::cache::Cache;
+use crate::cancel::AbortListener;
+use crate::progress::ProgressReporter;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use neon::prelude::*;
+use oxipng::{AlphaOptim, Deflaters, Headers, IndexSet};
+use std::env;
+use std::num::NonZeroU64;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// oxipng preset used when no `level` is given.
+const DEFAULT_LEVEL: u8 = 5;
+/// Time budget in milliseconds given to oxipng when no `timeout` is given.
+const DEFAULT_TIMEOUT_MS: u64 = 2000;
+/// Time budget in milliseconds when no `timeout` is given with the zopfli
+/// deflater, which is orders of magnitude slower and shares the budget.
+const DEFAULT_ZOPFLI_TIMEOUT_MS: u64 = 60_000;
+/// Zopfli iterations run when no `iterations` are given, the zopfli default.
+const DEFAULT_ZOPFLI_ITERATIONS: u64 = 15;
+
+/// Environment variable read for the number of worker threads when no
+/// `concurrency` is given.
+const THREADS_ENV: &str = "PNG_COMPRESS_THREADS";
+
+/// Keys of the source tree description passed to `compressDir`.
+const DIR_KEYS: &[&str] = &["src", "dest", "include", "exclude", "gitignore"];
+/// Glob matched against the relative paths when no `include` is given.
+const DEFAULT_INCLUDE: &str = "**/*.png";
+
+/// Keys which are only valid on the batch options object.
+const BATCH_KEYS: &[&str] = &[
+    "failFast",
+    "concurrency",
+    "largestFirst",
+    "onProgress",
+    "signal",
+    "cacheDir",
+    "manifest",
+    "dryRun",
+];
+/// Keys of the options which apply to every entry on its own, from the oxipng
+/// settings to the pre- and post-processing steps, which an entry can override.
+const COMPRESS_KEYS: &[&str] = &[
+    "level",
+    "timeout",
+    "filters",
+    "interlace",
+    "bitDepthReduction",
+    "colorTypeReduction",
+    "paletteReduction",
+    "alpha",
+    "force",
+    "minSavings",
+    "inPlace",
+    "preserveMtime",
+    "createDirs",
+    "incremental",
+    "verify",
+    "strip",
+    "lossy",
+    "deflate",
+    "formats",
+    "webp",
+    "avif",
+    "resize",
+];
+
+/// Batch level options passed as the optional second argument of `compress`.
+///
+/// * `failFast` - Stop at the first failing entry and throw (or reject with) its
+///   error instead of returning the results.
+/// * `concurrency` - Number of worker threads, see `resolve_concurrency`.
+/// * `largestFirst` - Start with the largest files instead of the order in which
+///   the entries were passed (default: true).
+/// * `onProgress` - Callback invoked for every finished entry, see
+///   `ProgressReporter`.
+/// * `signal` - `AbortSignal` which stops the workers from starting any more
+///   entries once it is aborted, see `AbortListener`. Not supported by the
+///   blocking `compress`.
+/// * `cacheDir` - Directory of the persistent cache of optimised images, see
+///   `Cache`.
+/// * `manifest` - JSON file recording what every output was produced from,
+///   used by the `incremental` mode, see `Manifest`.
+/// * `dryRun` - Run the whole pipeline without writing any output and report
+///   the projected savings instead.
+///
+/// Every other key is read into the `CompressOptions` applied to the entries.
+pub struct BatchOptions {
+    pub fail_fast: bool,
+    pub concurrency: usize,
+    pub largest_first: bool,
+    pub progress: Option<Arc<ProgressReporter>>,
+    /// Raised once the batch is cancelled through its `signal`.
+    pub cancelled: Arc<AtomicBool>,
+    pub signal: Option<AbortListener>,
+    pub cache: Option<Arc<Cache>>,
+    pub manifest: Option<PathBuf>,
+    pub dry_run: bool,
+    pub compress: CompressOptions,
+}
+
+/// Compression settings as passed from JS, either for the whole batch or as the
+/// `options` of a single entry. Every field is optional so that only the values
+/// which were actually set override the defaults.
+///
+/// * `level` - oxipng preset between 0 and 6, or "max" (default: 5)
+/// * `timeout` - time budget for a single file in milliseconds, including the
+///   zopfli pass, 0 disables it (default: 2000, or 60000 with the zopfli
+///   deflater)
+/// * `filters` - png filters to try, between 0 and 5
+/// * `interlace` - `true` to interlace the output, `false` to remove interlacing
+/// * `bitDepthReduction`, `colorTypeReduction`, `paletteReduction` - toggles for
+///   the oxipng reductions
+/// * `alpha` - `true` to try every alpha optimisation, or a list out of "black",
+///   "white", "up", "right", "down" and "left"
+/// * `force` - write the output even if it is not smaller than the input
+/// * `minSavings` - savings required to write the optimised image, either a
+///   number of bytes or a percentage such as "5%", see `MinSavings`
+/// * `inPlace` - atomically replace the input file instead of writing to `out`
+/// * `preserveMtime` - give the output the modification time of the input
+/// * `createDirs` - create the missing directories of `out` (default: true)
+/// * `incremental` - skip the entries whose output is up to date, either by
+///   the `manifest` of the batch or by being at least as new as the input
+/// * `verify` - decode the input and the optimised image and fail if their
+///   pixels differ, see `verify::verify`. Can not be combined with `lossy`
+/// * `strip` - metadata chunks to strip, "none", "safe", "all", or an object
+///   with a `keep` or `strip` list of chunk names (default: "none")
+/// * `lossy` - quantise truecolor images to a palette before optimising them,
+///   `true` or an object with the `quality` range and `dithering` level, see
+///   `Lossy`. `false` turns off the lossy mode of the batch
+/// * `deflate` - deflate implementation, "zlib", "libdeflater" or "zopfli", or
+///   an object with the `algorithm` and the zopfli `iterations`, see `Deflate`
+///   (default: "zlib")
+/// * `formats` - formats to write, "png" and any of "webp" and "avif", each
+///   extra format being written beside the png output, see `Format`
+/// * `webp` - settings of the WebP output, an object with `lossless` and
+///   `quality`, see `Webp`
+/// * `avif` - settings of the AVIF output, an object with `quality` and
+///   `speed`, see `Avif`
+/// * `resize` - resample the image before optimising it, an object with the
+///   target `width` and `height`, the `fit` and the `filter`, see `Resize`
+#[derive(Clone, Debug, Default)]
+pub struct CompressOptions {
+    pub level: Option<u8>,
+    pub timeout_ms: Option<u64>,
+    pub filters: Option<IndexSet<u8>>,
+    pub interlace: Option<u8>,
+    pub bit_depth_reduction: Option<bool>,
+    pub color_type_reduction: Option<bool>,
+    pub palette_reduction: Option<bool>,
+    pub alphas: Option<IndexSet<AlphaOptim>>,
+    pub force: Option<bool>,
+    pub min_savings: Option<MinSavings>,
+    pub in_place: Option<bool>,
+    pub preserve_mtime: Option<bool>,
+    pub create_dirs: Option<bool>,
+    pub incremental: Option<bool>,
+    pub verify: Option<bool>,
+    pub strip: Option<Headers>,
+    pub lossy: Option<Option<Lossy>>,
+    pub deflate: Option<Deflate>,
+    pub formats: Option<Vec<Format>>,
+    pub webp: Option<Webp>,
+    pub avif: Option<Avif>,
+    pub resize: Option<Resize>,
+}
+
+/// Settings of the lossy mode, which quantises truecolor images to a palette of
+/// at most 256 colours before the lossless pass, see `quantize::quantize`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Lossy {
+    /// Quality below which the quantised image is rejected, between 0 and 100.
+    pub min_quality: u8,
+    /// Quality at which no more colours are spent, between 0 and 100.
+    pub max_quality: u8,
+    /// Level of the error diffusion dithering, between 0 (off) and 1.
+    pub dithering: f32,
+}
+
+/// Deflate implementation used to compress the image data.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Deflate {
+    /// The zlib trials of the oxipng preset.
+    Zlib,
+    Libdeflater,
+    /// The zlib trials of the preset, after which the image data of the best
+    /// one is compressed again with zopfli, see `deflate::recompress`. oxipng
+    /// can neither set the number of zopfli iterations itself nor stop a
+    /// zopfli trial once its timeout passes.
+    Zopfli {
+        iterations: NonZeroU64,
+    },
+}
+
+/// Format written beside the optimised png, at the path of the png with the
+/// extension replaced by the `name` of the format.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Webp,
+    Avif,
+}
+
+impl Format {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Format::Webp => "webp",
+            Format::Avif => "avif",
+        }
+    }
+}
+
+/// Settings of the WebP output, see `formats::webp`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Webp {
+    pub lossless: bool,
+    /// Quality between 0 and 100, or the effort in lossless mode.
+    pub quality: f32,
+}
+
+impl Default for Webp {
+    fn default() -> Self {
+        Webp {
+            lossless: true,
+            quality: 75.0,
+        }
+    }
+}
+
+/// Settings of the AVIF output, see `formats::avif`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Avif {
+    /// Quality between 1 and 100.
+    pub quality: f32,
+    /// rav1e speed between 1 (slowest, smallest) and 10 (fastest).
+    pub speed: u8,
+}
+
+impl Default for Avif {
+    fn default() -> Self {
+        Avif {
+            quality: 80.0,
+            speed: 5,
+        }
+    }
+}
+
+/// Target size of the image, which is resampled before it is optimised, see
+/// `resize::resize`. At least one of `width` and `height` is set, the missing
+/// one follows the aspect ratio.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Resize {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fit: Fit,
+    pub filter: Filter,
+}
+
+/// How the image is fitted into a `Resize` box with both dimensions.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fit {
+    /// Keep the aspect ratio, as large as possible within the box.
+    Inside,
+    /// Keep the aspect ratio, covering the box and cropping the overflow
+    /// equally on both sides.
+    Cover,
+    /// Stretch the image to the box.
+    Fill,
+}
+
+/// Resampling filter of a `Resize`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+    Box,
+    Triangle,
+    CatmullRom,
+    Mitchell,
+    Lanczos3,
+}
+
+impl Default for Lossy {
+    fn default() -> Self {
+        Lossy {
+            min_quality: 0,
+            max_quality: 100,
+            dithering: 1.0,
+        }
+    }
+}
+
+/// Savings an optimised image has to reach to be written. When it falls short
+/// the original image is written instead.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MinSavings {
+    Bytes(u64),
+    Percent(f64),
+}
+
+impl MinSavings {
+    /// Whether going from `input_bytes` to `output_bytes` meets the threshold.
+    pub fn is_met(&self, input_bytes: u64, output_bytes: u64) -> bool {
+        let saved = input_bytes.saturating_sub(output_bytes);
+        match *self {
+            MinSavings::Bytes(bytes) => saved >= bytes,
+            MinSavings::Percent(percent) => saved as f64 * 100.0 >= percent * input_bytes as f64,
+        }
+    }
+}
+
+impl CompressOptions {
+    /// Returns these options with every value set in `overrides` replaced.
+    pub fn merge(&self, overrides: &CompressOptions) -> CompressOptions {
+        CompressOptions {
+            level: overrides.level.or(self.level),
+            timeout_ms: overrides.timeout_ms.or(self.timeout_ms),
+            filters: overrides.filters.clone().or_else(|| self.filters.clone()),
+            interlace: overrides.interlace.or(self.interlace),
+            bit_depth_reduction: overrides.bit_depth_reduction.or(self.bit_depth_reduction),
+            color_type_reduction: overrides.color_type_reduction.or(self.color_type_reduction),
+            palette_reduction: overrides.palette_reduction.or(self.palette_reduction),
+            alphas: overrides.alphas.clone().or_else(|| self.alphas.clone()),
+            force: overrides.force.or(self.force),
+            min_savings: overrides.min_savings.or(self.min_savings),
+            in_place: overrides.in_place.or(self.in_place),
+            preserve_mtime: overrides.preserve_mtime.or(self.preserve_mtime),
+            create_dirs: overrides.create_dirs.or(self.create_dirs),
+            incremental: overrides.incremental.or(self.incremental),
+            verify: overrides.verify.or(self.verify),
+            strip: overrides.strip.clone().or_else(|| self.strip.clone()),
+            lossy: overrides.lossy.or(self.lossy),
+            deflate: overrides.deflate.or(self.deflate),
+            formats: overrides.formats.clone().or_else(|| self.formats.clone()),
+            webp: overrides.webp.or(self.webp),
+            avif: overrides.avif.or(self.avif),
+            resize: overrides.resize.or(self.resize),
+        }
+    }
+
+    /// Iterations of the zopfli pass following oxipng, if it runs at all.
+    pub fn zopfli_iterations(&self) -> Option<NonZeroU64> {
+        match self.deflate {
+            Some(Deflate::Zopfli { iterations }) => Some(iterations),
+            _ => None,
+        }
+    }
+
+    /// Builds the `oxipng::Options` by applying the set values on top of the preset.
+    pub fn to_oxipng(&self) -> oxipng::Options {
+        let mut options = oxipng::Options::from_preset(self.level.unwrap_or(DEFAULT_LEVEL));
+        let default_timeout_ms = match self.deflate {
+            Some(Deflate::Zopfli { .. }) => DEFAULT_ZOPFLI_TIMEOUT_MS,
+            _ => DEFAULT_TIMEOUT_MS,
+        };
+        options.timeout = match self.timeout_ms.unwrap_or(default_timeout_ms) {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        };
+        if let Some(filters) = &self.filters {
+            options.filter = filters.clone();
+        }
+        if self.interlace.is_some() {
+            options.interlace = self.interlace;
+        }
+        if let Some(bit_depth_reduction) = self.bit_depth_reduction {
+            options.bit_depth_reduction = bit_depth_reduction;
+        }
+        if let Some(color_type_reduction) = self.color_type_reduction {
+            options.color_type_reduction = color_type_reduction;
+        }
+        if let Some(palette_reduction) = self.palette_reduction {
+            options.palette_reduction = palette_reduction;
+        }
+        if let Some(alphas) = &self.alphas {
+            options.alphas = alphas.clone();
+        }
+        if let Some(force) = self.force {
+            options.force = force;
+        }
+        if let Some(strip) = &self.strip {
+            options.strip = strip.clone();
+        }
+        if let Some(Deflate::Libdeflater) = self.deflate {
+            options.deflate = Deflaters::Libdeflater;
+        }
+        options
+    }
+}
+
+/// Reads the optional `BatchOptions` object. Throws a `TypeError` for unknown
+/// keys or values of the wrong type and a `RangeError` for values out of range.
+pub fn parse_batch_options<'a, C: Context<'a>>(
+    cx: &mut C,
+    value: Option<Handle<'a, JsValue>>,
+) -> NeonResult<BatchOptions> {
+    let js_object = match value {
+        Some(val) if !val.is_a::<JsUndefined, _>(cx) => match val.downcast::<JsObject, _>(cx) {
+            Ok(js_object) => js_object,
+            Err(_) => return cx.throw_type_error("Options must be an object"),
+        },
+        _ => {
+            return Ok(BatchOptions {
+                fail_fast: false,
+                concurrency: resolve_concurrency(cx, None)?,
+                largest_first: true,
+                progress: None,
+                cancelled: Arc::new(AtomicBool::new(false)),
+                signal: None,
+                cache: None,
+                manifest: None,
+                dry_run: false,
+                compress: CompressOptions::default(),
+            })
+        }
+    };
+    check_keys(cx, js_object, &[BATCH_KEYS, COMPRESS_KEYS])?;
+
+    let concurrency = match get_number(cx, js_object, "concurrency")? {
+        Some(concurrency) if concurrency.fract() == 0.0 && concurrency >= 1.0 => {
+            Some(concurrency as usize)
+        }
+        Some(_) => return cx.throw_range_error("Option `concurrency` must be a positive integer"),
+        None => None,
+    };
+    Ok(BatchOptions {
+        fail_fast: get_bool(cx, js_object, "failFast")?.unwrap_or(false),
+        concurrency: resolve_concurrency(cx, concurrency)?,
+        largest_first: get_bool(cx, js_object, "largestFirst")?.unwrap_or(true),
+        progress: get_progress(cx, js_object)?,
+        cancelled: Arc::new(AtomicBool::new(false)),
+        signal: None,
+        cache: get_cache(cx, js_object)?,
+        manifest: match get_defined(cx, js_object, "manifest")? {
+            Some(value) => match value.downcast::<JsString, _>(cx).map(|path| path.value(cx)) {
+                Ok(path) if !path.is_empty() => Some(PathBuf::from(path)),
+                _ => return cx.throw_type_error("Option `manifest` must be a non-empty string"),
+            },
+            None => None,
+        },
+        dry_run: get_bool(cx, js_object, "dryRun")?.unwrap_or(false),
+        compress: read_compress_options(cx, js_object)?,
+    })
+}
+
+/// Registers the `signal` of the `BatchOptions` object `value` on the batch,
+/// see `AbortListener`. `parse_batch_options` leaves it out, as the entry
+/// points have to call this once all of their checks passed, so no listener is
+/// left behind when one of them throws.
+pub fn watch_signal<'a, C: Context<'a>>(
+    cx: &mut C,
+    value: Option<Handle<'a, JsValue>>,
+    options: &mut BatchOptions,
+) -> NeonResult<()> {
+    let js_object = match value.map(|value| value.downcast::<JsObject, _>(cx)) {
+        Some(Ok(js_object)) => js_object,
+        _ => return Ok(()),
+    };
+    if let Some(signal) = get_defined(cx, js_object, "signal")? {
+        options.signal = Some(AbortListener::watch(cx, signal, &options.cancelled)?);
+    }
+    Ok(())
+}
+
+/// Whether the `BatchOptions` object `value` has a `signal`, which `compress`
+/// rejects as the event loop, and so the abort listener, is blocked until it
+/// returns.
+pub fn has_signal<'a, C: Context<'a>>(
+    cx: &mut C,
+    value: Option<Handle<'a, JsValue>>,
+) -> NeonResult<bool> {
+    match value.map(|value| value.downcast::<JsObject, _>(cx)) {
+        Some(Ok(js_object)) => Ok(get_defined(cx, js_object, "signal")?.is_some()),
+        _ => Ok(false),
+    }
+}
+
+/// Source tree passed as the first argument of `compressDir`.
+///
+/// * `src` - Directory which is walked for images.
+/// * `dest` - Directory the relative structure of `src` is mirrored into. Left
+///   out with the `inPlace` option.
+/// * `include` - Globs the path relative to `src` has to match (default:
+///   "**/*.png").
+/// * `exclude` - Globs of relative paths which are skipped.
+/// * `gitignore` - Skip the files ignored by the `.gitignore` files found in the
+///   tree (default: false).
+pub struct DirOptions {
+    pub src: PathBuf,
+    pub dest: Option<PathBuf>,
+    pub include: GlobSet,
+    pub exclude: GlobSet,
+    pub gitignore: bool,
+}
+
+/// Reads the `DirOptions` object. Throws a `TypeError` for unknown keys or
+/// values of the wrong type and a `RangeError` for invalid globs.
+pub fn parse_dir_options<'a, C: Context<'a>>(
+    cx: &mut C,
+    value: Option<Handle<'a, JsValue>>,
+) -> NeonResult<DirOptions> {
+    let js_object = match value.map(|val| val.downcast::<JsObject, _>(cx)) {
+        Some(Ok(js_object)) => js_object,
+        _ => {
+            return cx.throw_type_error(
+                "Expected an object with a `src` directory as the first argument",
+            )
+        }
+    };
+    if let Some(key) = find_unknown_key(cx, js_object, &[DIR_KEYS])? {
+        return cx.throw_type_error(format!("Unknown field `{}`", key));
+    }
+    let src = match get_path(cx, js_object, "src")? {
+        Some(src) => src,
+        None => return cx.throw_type_error("Field `src` must be a non-empty string"),
+    };
+    let include = match get_globs(cx, js_object, "include")? {
+        Some(include) => include,
+        None => vec![DEFAULT_INCLUDE.to_string()],
+    };
+    let exclude = get_globs(cx, js_object, "exclude")?.unwrap_or_default();
+    Ok(DirOptions {
+        src,
+        dest: get_path(cx, js_object, "dest")?,
+        include: build_glob_set(cx, "include", &include)?,
+        exclude: build_glob_set(cx, "exclude", &exclude)?,
+        gitignore: get_bool(cx, js_object, "gitignore")?.unwrap_or(false),
+    })
+}
+
+/// Resolves the number of worker threads at call time. An explicit
+/// `concurrency` option wins over the `PNG_COMPRESS_THREADS` environment
+/// variable, which in turn wins over the available parallelism of the machine.
+/// Throws a `RangeError` if the environment variable is not a positive integer.
+fn resolve_concurrency<'a, C: Context<'a>>(
+    cx: &mut C,
+    concurrency: Option<usize>,
+) -> NeonResult<usize> {
+    if let Some(concurrency) = concurrency {
+        return Ok(concurrency);
+    }
+    match env::var(THREADS_ENV) {
+        Ok(value) => match value.trim().parse::<usize>() {
+            Ok(threads) if threads > 0 => Ok(threads),
+            _ => cx.throw_range_error(format!(
+                "Environment variable {} must be a positive integer, got `{}`",
+                THREADS_ENV, value
+            )),
+        },
+        Err(env::VarError::NotUnicode(_)) => cx.throw_range_error(format!(
+            "Environment variable {} must be a positive integer",
+            THREADS_ENV
+        )),
+        Err(env::VarError::NotPresent) => Ok(thread::available_parallelism()
+            .map(|threads| threads.get())
+            .unwrap_or(1)),
+    }
+}
+
+/// Reads an options object which only accepts the `COMPRESS_KEYS`, such as the
+/// `options` of a single entry.
+pub fn parse_compress_options<'a, C: Context<'a>>(
+    cx: &mut C,
+    value: Handle<'a, JsValue>,
+) -> NeonResult<CompressOptions> {
+    if value.is_a::<JsUndefined, _>(cx) {
+        return Ok(CompressOptions::default());
+    }
+    let js_object = match value.downcast::<JsObject, _>(cx) {
+        Ok(js_object) => js_object,
+        Err(_) => return cx.throw_type_error("Options must be an object"),
+    };
+    check_keys(cx, js_object, &[COMPRESS_KEYS])?;
+
+    read_compress_options(cx, js_object)
+}
+
+fn read_compress_options<'a, C: Context<'a>>(
+    cx: &mut C,
+    js_object: Handle<'a, JsObject>,
+) -> NeonResult<CompressOptions> {
+    Ok(CompressOptions {
+        level: get_level(cx, js_object)?,
+        timeout_ms: get_number(cx, js_object, "timeout")?.map(|ms| ms.ceil() as u64),
+        filters: get_filters(cx, js_object)?,
+        interlace: get_bool(cx, js_object, "interlace")?.map(u8::from),
+        bit_depth_reduction: get_bool(cx, js_object, "bitDepthReduction")?,
+        color_type_reduction: get_bool(cx, js_object, "colorTypeReduction")?,
+        palette_reduction: get_bool(cx, js_object, "paletteReduction")?,
+        alphas: get_alphas(cx, js_object)?,
+        force: get_bool(cx, js_object, "force")?,
+        min_savings: get_min_savings(cx, js_object)?,
+        in_place: get_bool(cx, js_object, "inPlace")?,
+        preserve_mtime: get_bool(cx, js_object, "preserveMtime")?,
+        create_dirs: get_bool(cx, js_object, "createDirs")?,
+        incremental: get_bool(cx, js_object, "incremental")?,
+        verify: get_bool(cx, js_object, "verify")?,
+        strip: get_strip(cx, js_object)?,
+        lossy: get_lossy(cx, js_object)?,
+        deflate: get_deflate(cx, js_object)?,
+        formats: get_formats(cx, js_object)?,
+        webp: get_webp(cx, js_object)?,
+        avif: get_avif(cx, js_object)?,
+        resize: get_resize(cx, js_object)?,
+    })
+}
+
+/// Throws a `TypeError` naming the first key of `js_object` which is not part
+/// of any of the `allowed` key lists.
+fn check_keys<'a, C: Context<'a>>(
+    cx: &mut C,
+    js_object: Handle<'a, JsObject>,
+    allowed: &[&[&str]],
+) -> NeonResult<()> {
+    match find_unknown_key(cx, js_object, allowed)? {
+        Some(key) => cx.throw_type_error(format!("Unknown option `{}`", key)),
+        None => Ok(()),
+    }
+}
+
+/// Returns the first key of `js_object` which is not part of any of the
+/// `allowed` key lists.
+pub fn find_unknown_key<'a, C: Context<'a>>(
+    cx: &mut C,
+    js_object: Handle<'a, JsObject>,
+    allowed: &[&[&str]],
+) -> NeonResult<Option<String>> {
+    let keys = js_object.get_own_property_names(cx)?.to_vec(cx)?;
+    for key in keys {
+        let key = key.to_string(cx)?.value(cx);
+        if !allowed.iter().any(|keys| keys.contains(&key.as_str())) {
+            return Ok(Some(key));
+        }
+    }
+    Ok(None)
+}
+
+fn get_defined<'a, C: Context<'a>>(
+    cx: &mut C,
+    js_object: Handle<'a, JsObject>,
+    key: &str,
+) -> NeonResult<Option<Handle<'a, JsValue>>> {
+    let value = js_object.get_value(cx, key)?;
+    if value.is_a::<JsUndefined, _>(cx) {
+        return Ok(None);
+    }
+    Ok(Some(value))
+}
+
+fn get_bool<'a, C: Context<'a>>(
+    cx: &mut C,
+    js_object: Handle<'a, JsObject>,
+    key: &str,
+) -> NeonResult<Option<bool>> {
+    match get_defined(cx, js_object, key)? {
+        Some(value) => match value.downcast::<JsBoolean, _>(cx) {
+            Ok(value) => Ok(Some(value.value(cx))),
+            Err(_) => cx.throw_type_error(format!("Option `{}` must be a boolean", key)),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Reads a non-negative number.
+fn get_number<'a, C: Context<'a>>(
+    cx: &mut C,
+    js_object: Handle<'a, JsObject>,
+    key: &str,
+) -> NeonResult<Option<f64>> {
+    let value = match get_defined(cx, js_object, key)? {
+        Some(value) => match value.downcast::<JsNumber, _>(cx) {
+            Ok(value) => value.value(cx),
+            Err(_) => return cx.throw_type_error(format!("Option `{}` must be a number", key)),
+        },
+        None => return Ok(None),
+    };
+    if !value.is_finite() || value < 0.0 {
+        return cx.throw_range_error(format!("Option `{}` must be a non-negative number", key));
+    }
+    Ok(Some(value))
+}
+
+fn get_progress<'a, C: Context<'a>>(
+    cx: &mut C,
+    js_object: Handle<'a, JsObject>,
+) -> NeonResult<Option<Arc<ProgressReporter>>> {
+    match get_defined(cx, js_object, "onProgress")? {
+        Some(value) => match value.downcast::<JsFunction, _>(cx) {
+            Ok(callback) => Ok(Some(Arc::new(ProgressReporter::new(cx, callback)))),
+            Err(_) => cx.throw_type_error("Option `onProgress` must be a function"),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Reads a non-empty path string.
+fn get_path<'a, C: Context<'a>>(
+    cx: &mut C,
+    js_object: Handle<'a, JsObject>,
+    key: &str,
+) -> NeonResult<Option<PathBuf>> {
+    match get_defined(cx, js_object, key)? {
+        Some(value) => match value.downcast::<JsString, _>(cx).map(|path| path.value(cx)) {
+            Ok(path) if !path.is_empty() => Ok(Some(PathBuf::from(path))),
+            _ => cx.throw_type_error(format!("Field `{}` must be a non-empty string", key)),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Reads a single glob or an array of globs.
+fn get_globs<'a, C: Context<'a>>(
+    cx: &mut C,
+    js_object: Handle<'a, JsObject>,
+    key: &str,
+) -> NeonResult<Option<Vec<String>>> {
+    let value = match get_defined(cx, js_object, key)? {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let values = match value.downcast::<JsArray, _>(cx) {
+        Ok(arr) => arr.to_vec(cx)?,
+        Err(_) => vec![value],
+    };
+    let mut globs = vec![];
+    for value in values {
+        match value.downcast::<JsString, _>(cx) {
+            Ok(glob) => globs.push(glob.value(cx)),
+            Err(_) => {
+                return cx.throw_type_error(format!(
+                    "Field `{}` must be a glob or an array of globs",
+                    key
+                ))
+            }
+        }
+    }
+    Ok(Some(globs))
+}
+
+fn build_glob_set<'a, C: Context<'a>>(
+    cx: &mut C,
+    key: &str,
+    globs: &[String],
+) -> NeonResult<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        match Glob::new(glob) {
+            Ok(glob) => builder.add(glob),
+            Err(err) => {
+                return cx.throw_range_error(format!("Invalid glob in field `{}`: {}", key, err))
+            }
+        };
+    }
+    match builder.build() {
+        Ok(set) => Ok(set),
+        Err(err) => cx.throw_range_error(format!("Invalid glob in field `{}`: {}", key, err)),
+    }
+}
+
+fn get_cache<'a, C: Context<'a>>(
+    cx: &mut C,
+    js_object: Handle<'a, JsObject>,
+) -> NeonResult<Option<Arc<Cache>>> {
+    match get_defined(cx, js_object, "cacheDir")? {
+        Some(value) => match value.downcast::<JsString, _>(cx).map(|dir| dir.value(cx)) {
+            Ok(dir) if !dir.is_empty() => Ok(Some(Arc::new(Cache::new(PathBuf::from(dir))))),
+            _ => cx.throw_type_error("Option `cacheDir` must be a non-empty string"),
+        },
+        None => Ok(None),
+    }
+}
+
+fn get_level<'a, C: Context<'a>>(
+    cx: &mut C,
+    js_object: Handle<'a, JsObject>,
+) -> NeonResult<Option<u8>> {
+    let value = match get_defined(cx, js_object, "level")? {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    if let Ok(level) = value.downcast::<JsString, _>(cx) {
+        if level.value(cx) == "max" {
+            return Ok(Some(6));
+        }
+    } else if let Ok(level) = value.downcast::<JsNumber, _>(cx) {
+        let level = level.value(cx);
+        if level.fract() == 0.0 && (0.0..=6.0).contains(&level) {
+            return Ok(Some(level as u8));
+        }
+    }
+    cx.throw_range_error("Option `level` must be an integer between 0 and 6 or \"max\"")
+}
+
+/// Reads `minSavings`, either a whole number of bytes or a percentage string
+/// between "0%" and "100%".
+fn get_min_savings<'a, C: Context<'a>>(
+    cx: &mut C,
+    js_object: Handle<'a, JsObject>,
+) -> NeonResult<Option<MinSavings>> {
+    let value = match get_defined(cx, js_object, "minSavings")? {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    if let Ok(bytes) = value.downcast::<JsNumber, _>(cx) {
+        let bytes = bytes.value(cx);
+        if bytes.fract() != 0.0 || bytes < 0.0 || !bytes.is_finite() {
+            return cx.throw_range_error("Option `minSavings` must be a non-negative integer");
+        }
+        return Ok(Some(MinSavings::Bytes(bytes as u64)));
+    }
+    if let Ok(percent) = value.downcast::<JsString, _>(cx) {
+        let percent = percent.value(cx);
+        return match percent
+            .strip_suffix('%')
+            .and_then(|percent| percent.trim().parse::<f64>().ok())
+        {
+            Some(percent) if (0.0..=100.0).contains(&percent) => {
+                Ok(Some(MinSavings::Percent(percent)))
+            }
+            _ => cx.throw_range_error(format!(
+                "Option `minSavings` must be a percentage between \"0%\" and \"100%\", got `{}`",
+                percent
+            )),
+        };
+    }
+    cx.throw_type_error("Option `minSavings` must be a number of bytes or a percentage string")
+}
+
+fn get_filters<'a, C: Context<'a>>(
+    cx: &mut C,
+    js_object: Handle<'a, JsObject>,
+) -> NeonResult<Option<IndexSet<u8>>> {
+    let value = match get_defined(cx, js_object, "filters")? {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let values = match value.downcast::<JsArray, _>(cx) {
+        Ok(arr) => arr.to_vec(cx)?,
+        Err(_) => return cx.throw_type_error("Option `filters` must be an array of numbers"),
+    };
+
+    let mut filters = IndexSet::new();
+    for value in values {
+        let filter = match value.downcast::<JsNumber, _>(cx) {
+            Ok(filter) => filter.value(cx),
+            Err(_) => return cx.throw_type_error("Option `filters` must be an array of numbers"),
+        };
+        if filter.fract() != 0.0 || !(0.0..=5.0).contains(&filter) {
+            return cx
+                .throw_range_error("Option `filters` must only contain integers between 0 and 5");
+        }
+        filters.insert(filter as u8);
+    }
+    if filters.is_empty() {
+        return cx.throw_range_error("Option `filters` must not be empty");
+    }
+    Ok(Some(filters))
+}
+
+/// Reads `strip`, either the name of a policy or an object with the list of
+/// chunks to `keep` (stripping every other ancillary chunk) or to `strip`.
+fn get_strip<'a, C: Context<'a>>(
+    cx: &mut C,
+    js_object: Handle<'a, JsObject>,
+) -> NeonResult<Option<Headers>> {
+    let value = match get_defined(cx, js_object, "strip")? {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    if let Ok(policy) = value.downcast::<JsString, _>(cx) {
+        let policy = policy.value(cx);
+        return match policy.as_str() {
+            "none" => Ok(Some(Headers::None)),
+            "safe" => Ok(Some(Headers::Safe)),
+            "all" => Ok(Some(Headers::All)),
+            _ => cx.throw_range_error(format!(
+                "Option `strip` must be \"none\", \"safe\" or \"all\", got `{}`",
+                policy
+            )),
+        };
+    }
+
+    let lists = match value.downcast::<JsObject, _>(cx) {
+        Ok(lists) if !value.is_a::<JsArray, _>(cx) => lists,
+        _ => {
+            return cx.throw_type_error(
+                "Option `strip` must be a string or an object with a `keep` or `strip` list",
+            )
+        }
+    };
+    if let Some(key) = find_unknown_key(cx, lists, &[&["keep", "strip"]])? {
+        return cx.throw_type_error(format!("Unknown field `{}` in option `strip`", key));
+    }
+    match (
+        get_chunk_names(cx, lists, "keep")?,
+        get_chunk_names(cx, lists, "strip")?,
+    ) {
+        (Some(keep), None) => Ok(Some(Headers::Keep(keep.into_iter().collect()))),
+        (None, Some(strip)) => {
+            if let Some(name) = strip.iter().find(|name| is_critical_chunk(name)) {
+                return cx
+                    .throw_range_error(format!("Critical chunk `{}` can not be stripped", name));
+            }
+            Ok(Some(Headers::Strip(strip)))
+        }
+        _ => cx.throw_type_error("Option `strip` must have either a `keep` or a `strip` list"),
+    }
+}
+
+/// Reads `lossy`, either a boolean or an object with the `quality` range as an
+/// array of two integers between 0 and 100 and the `dithering` level as a
+/// boolean or a number between 0 and 1.
+fn get_lossy<'a, C: Context<'a>>(
+    cx: &mut C,
+    js_object: Handle<'a, JsObject>,
+) -> NeonResult<Option<Option<Lossy>>> {
+    let value = match get_defined(cx, js_object, "lossy")? {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    if let Ok(enabled) = value.downcast::<JsBoolean, _>(cx) {
+        return Ok(Some(Some(Lossy::default()).filter(|_| enabled.value(cx))));
+    }
+    let settings = match value.downcast::<JsObject, _>(cx) {
+        Ok(settings) if !value.is_a::<JsArray, _>(cx) => settings,
+        _ => return cx.throw_type_error("Option `lossy` must be a boolean or an object"),
+    };
+    if let Some(key) = find_unknown_key(cx, settings, &[&["quality", "dithering"]])? {
+        return cx.throw_type_error(format!("Unknown field `{}` in option `lossy`", key));
+    }
+
+    let mut lossy = Lossy::default();
+    if let Some(quality) = get_defined(cx, settings, "quality")? {
+        let range = match quality.downcast::<JsArray, _>(cx) {
+            Ok(range) => range.to_vec(cx)?,
+            Err(_) => {
+                return cx.throw_type_error(
+                    "Field `quality` of option `lossy` must be an array of two numbers",
+                )
+            }
+        };
+        let mut bounds = vec![];
+        for bound in range {
+            match bound.downcast::<JsNumber, _>(cx) {
+                Ok(bound) => bounds.push(bound.value(cx)),
+                Err(_) => {
+                    return cx.throw_type_error(
+                        "Field `quality` of option `lossy` must be an array of two numbers",
+                    )
+                }
+            }
+        }
+        match *bounds {
+            [min, max]
+                if [min, max]
+                    .iter()
+                    .all(|bound| bound.fract() == 0.0 && (0.0..=100.0).contains(bound))
+                    && min <= max =>
+            {
+                lossy.min_quality = min as u8;
+                lossy.max_quality = max as u8;
+            }
+            _ => {
+                return cx.throw_range_error(
+                    "Field `quality` of option `lossy` must be a `[min, max]` range of integers between 0 and 100",
+                )
+            }
+        }
+    }
+    if let Some(dithering) = get_defined(cx, settings, "dithering")? {
+        if let Ok(enabled) = dithering.downcast::<JsBoolean, _>(cx) {
+            lossy.dithering = if enabled.value(cx) { 1.0 } else { 0.0 };
+        } else if let Ok(level) = dithering.downcast::<JsNumber, _>(cx) {
+            let level = level.value(cx);
+            if !(0.0..=1.0).contains(&level) {
+                return cx.throw_range_error(
+                    "Field `dithering` of option `lossy` must be between 0 and 1",
+                );
+            }
+            lossy.dithering = level as f32;
+        } else {
+            return cx.throw_type_error(
+                "Field `dithering` of option `lossy` must be a boolean or a number",
+            );
+        }
+    }
+    Ok(Some(Some(lossy)))
+}
+
+/// Reads `deflate`, either the name of the algorithm or an object with the
+/// `algorithm` and, for zopfli, the number of `iterations`.
+fn get_deflate<'a, C: Context<'a>>(
+    cx: &mut C,
+    js_object: Handle<'a, JsObject>,
+) -> NeonResult<Option<Deflate>> {
+    let value = match get_defined(cx, js_object, "deflate")? {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let (algorithm, settings) = if let Ok(algorithm) = value.downcast::<JsString, _>(cx) {
+        (algorithm.value(cx), None)
+    } else {
+        let settings = match value.downcast::<JsObject, _>(cx) {
+            Ok(settings) if !value.is_a::<JsArray, _>(cx) => settings,
+            _ => return cx.throw_type_error("Option `deflate` must be a string or an object"),
+        };
+        if let Some(key) = find_unknown_key(cx, settings, &[&["algorithm", "iterations"]])? {
+            return cx.throw_type_error(format!("Unknown field `{}` in option `deflate`", key));
+        }
+        let algorithm = match get_defined(cx, settings, "algorithm")?
+            .map(|algorithm| algorithm.downcast::<JsString, _>(cx))
+        {
+            Some(Ok(algorithm)) => algorithm.value(cx),
+            _ => {
+                return cx
+                    .throw_type_error("Field `algorithm` of option `deflate` must be a string")
+            }
+        };
+        (algorithm, Some(settings))
+    };
+
+    let iterations = match settings {
+        Some(settings) => get_defined(cx, settings, "iterations")?,
+        None => None,
+    };
+    match algorithm.as_str() {
+        "zlib" | "libdeflater" if iterations.is_some() => cx.throw_type_error(
+            "Field `iterations` of option `deflate` is only supported by \"zopfli\"",
+        ),
+        "zlib" => Ok(Some(Deflate::Zlib)),
+        "libdeflater" => Ok(Some(Deflate::Libdeflater)),
+        "zopfli" => {
+            let iterations = match iterations {
+                Some(iterations) => match iterations.downcast::<JsNumber, _>(cx) {
+                    Ok(iterations) => iterations.value(cx),
+                    Err(_) => {
+                        return cx.throw_type_error(
+                            "Field `iterations` of option `deflate` must be a number",
+                        )
+                    }
+                },
+                None => DEFAULT_ZOPFLI_ITERATIONS as f64,
+            };
+            if iterations.fract() != 0.0 || !(1.0..=u32::MAX as f64).contains(&iterations) {
+                return cx.throw_range_error(
+                    "Field `iterations` of option `deflate` must be a positive integer",
+                );
+            }
+            Ok(NonZeroU64::new(iterations as u64).map(|iterations| Deflate::Zopfli { iterations }))
+        }
+        _ => cx.throw_range_error(format!(
+            "Option `deflate` must be \"zlib\", \"libdeflater\" or \"zopfli\", got `{}`",
+            algorithm
+        )),
+    }
+}
+
+/// Reads `formats`, an array of format names which has to include "png". The
+/// returned list only holds the extra formats.
+fn get_formats<'a, C: Context<'a>>(
+    cx: &mut C,
+    js_object: Handle<'a, JsObject>,
+) -> NeonResult<Option<Vec<Format>>> {
+    let value = match get_defined(cx, js_object, "formats")? {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let values = match value.downcast::<JsArray, _>(cx) {
+        Ok(arr) => arr.to_vec(cx)?,
+        Err(_) => return cx.throw_type_error("Option `formats` must be an array of strings"),
+    };
+    let mut has_png = false;
+    let mut formats = vec![];
+    for value in values {
+        let name = match value.downcast::<JsString, _>(cx) {
+            Ok(name) => name.value(cx),
+            Err(_) => return cx.throw_type_error("Option `formats` must be an array of strings"),
+        };
+        let format = match name.as_str() {
+            "png" => {
+                has_png = true;
+                continue;
+            }
+            "webp" => Format::Webp,
+            "avif" => Format::Avif,
+            _ => {
+                return cx
+                    .throw_range_error(format!("Unknown format `{}` in option `formats`", name))
+            }
+        };
+        if !formats.contains(&format) {
+            formats.push(format);
+        }
+    }
+    if !has_png {
+        return cx.throw_range_error("Option `formats` must include \"png\"");
+    }
+    Ok(Some(formats))
+}
+
+/// Reads `webp`, an object with the `lossless` toggle and the `quality`
+/// between 0 and 100.
+fn get_webp<'a, C: Context<'a>>(
+    cx: &mut C,
+    js_object: Handle<'a, JsObject>,
+) -> NeonResult<Option<Webp>> {
+    let value = match get_defined(cx, js_object, "webp")? {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let settings = match value.downcast::<JsObject, _>(cx) {
+        Ok(settings) if !value.is_a::<JsArray, _>(cx) => settings,
+        _ => return cx.throw_type_error("Option `webp` must be an object"),
+    };
+    if let Some(key) = find_unknown_key(cx, settings, &[&["lossless", "quality"]])? {
+        return cx.throw_type_error(format!("Unknown field `{}` in option `webp`", key));
+    }
+    let mut webp = Webp::default();
+    if let Some(lossless) = get_defined(cx, settings, "lossless")? {
+        match lossless.downcast::<JsBoolean, _>(cx) {
+            Ok(lossless) => webp.lossless = lossless.value(cx),
+            Err(_) => {
+                return cx.throw_type_error("Field `lossless` of option `webp` must be a boolean")
+            }
+        }
+    }
+    if let Some(quality) = get_defined(cx, settings, "quality")? {
+        let quality = match quality.downcast::<JsNumber, _>(cx) {
+            Ok(quality) => quality.value(cx),
+            Err(_) => {
+                return cx.throw_type_error("Field `quality` of option `webp` must be a number")
+            }
+        };
+        if !(0.0..=100.0).contains(&quality) {
+            return cx
+                .throw_range_error("Field `quality` of option `webp` must be between 0 and 100");
+        }
+        webp.quality = quality as f32;
+    }
+    Ok(Some(webp))
+}
+
+/// Reads `avif`, an object with the `quality` between 1 and 100 and the
+/// integer `speed` between 1 and 10.
+fn get_avif<'a, C: Context<'a>>(
+    cx: &mut C,
+    js_object: Handle<'a, JsObject>,
+) -> NeonResult<Option<Avif>> {
+    let value = match get_defined(cx, js_object, "avif")? {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let settings = match value.downcast::<JsObject, _>(cx) {
+        Ok(settings) if !value.is_a::<JsArray, _>(cx) => settings,
+        _ => return cx.throw_type_error("Option `avif` must be an object"),
+    };
+    if let Some(key) = find_unknown_key(cx, settings, &[&["quality", "speed"]])? {
+        return cx.throw_type_error(format!("Unknown field `{}` in option `avif`", key));
+    }
+    let mut avif = Avif::default();
+    if let Some(quality) = get_defined(cx, settings, "quality")? {
+        let quality = match quality.downcast::<JsNumber, _>(cx) {
+            Ok(quality) => quality.value(cx),
+            Err(_) => {
+                return cx.throw_type_error("Field `quality` of option `avif` must be a number")
+            }
+        };
+        if !(1.0..=100.0).contains(&quality) {
+            return cx
+                .throw_range_error("Field `quality` of option `avif` must be between 1 and 100");
+        }
+        avif.quality = quality as f32;
+    }
+    if let Some(speed) = get_defined(cx, settings, "speed")? {
+        let speed = match speed.downcast::<JsNumber, _>(cx) {
+            Ok(speed) => speed.value(cx),
+            Err(_) => {
+                return cx.throw_type_error("Field `speed` of option `avif` must be a number")
+            }
+        };
+        if speed.fract() != 0.0 || !(1.0..=10.0).contains(&speed) {
+            return cx.throw_range_error(
+                "Field `speed` of option `avif` must be an integer between 1 and 10",
+            );
+        }
+        avif.speed = speed as u8;
+    }
+    Ok(Some(avif))
+}
+
+/// Reads `resize`, an object with the `width` and `height` in pixels, of which
+/// at least one is required, the `fit` and the `filter`.
+fn get_resize<'a, C: Context<'a>>(
+    cx: &mut C,
+    js_object: Handle<'a, JsObject>,
+) -> NeonResult<Option<Resize>> {
+    let value = match get_defined(cx, js_object, "resize")? {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let settings = match value.downcast::<JsObject, _>(cx) {
+        Ok(settings) if !value.is_a::<JsArray, _>(cx) => settings,
+        _ => return cx.throw_type_error("Option `resize` must be an object"),
+    };
+    if let Some(key) = find_unknown_key(cx, settings, &[&["width", "height", "fit", "filter"]])? {
+        return cx.throw_type_error(format!("Unknown field `{}` in option `resize`", key));
+    }
+    let width = get_dimension(cx, settings, "width")?;
+    let height = get_dimension(cx, settings, "height")?;
+    if width.is_none() && height.is_none() {
+        return cx.throw_type_error("Option `resize` must have a `width` or a `height`");
+    }
+
+    let fit = match get_resize_string(cx, settings, "fit")?.as_deref() {
+        None | Some("inside") => Fit::Inside,
+        Some("cover") => Fit::Cover,
+        Some("fill") => Fit::Fill,
+        Some(fit) => {
+            return cx.throw_range_error(format!(
+            "Field `fit` of option `resize` must be \"inside\", \"cover\" or \"fill\", got `{}`",
+            fit
+        ))
+        }
+    };
+    let filter = match get_resize_string(cx, settings, "filter")?.as_deref() {
+        None | Some("lanczos3") => Filter::Lanczos3,
+        Some("mitchell") => Filter::Mitchell,
+        Some("catmullRom") => Filter::CatmullRom,
+        Some("triangle") => Filter::Triangle,
+        Some("box") => Filter::Box,
+        Some(filter) => {
+            return cx.throw_range_error(format!(
+                "Field `filter` of option `resize` must be \"lanczos3\", \"mitchell\", \"catmullRom\", \"triangle\" or \"box\", got `{}`",
+                filter
+            ))
+        }
+    };
+    Ok(Some(Resize {
+        width,
+        height,
+        fit,
+        filter,
+    }))
+}
+
+/// Reads the `key` dimension of `resize`, a positive integer which png allows
+/// up to 2^31 - 1.
+fn get_dimension<'a, C: Context<'a>>(
+    cx: &mut C,
+    settings: Handle<'a, JsObject>,
+    key: &str,
+) -> NeonResult<Option<u32>> {
+    let value = match get_defined(cx, settings, key)? {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let dimension = match value.downcast::<JsNumber, _>(cx) {
+        Ok(dimension) => dimension.value(cx),
+        Err(_) => {
+            return cx.throw_type_error(format!(
+                "Field `{}` of option `resize` must be a number",
+                key
+            ))
+        }
+    };
+    if dimension.fract() != 0.0 || !(1.0..=i32::MAX as f64).contains(&dimension) {
+        return cx.throw_range_error(format!(
+            "Field `{}` of option `resize` must be a positive integer",
+            key
+        ));
+    }
+    Ok(Some(dimension as u32))
+}
+
+fn get_resize_string<'a, C: Context<'a>>(
+    cx: &mut C,
+    settings: Handle<'a, JsObject>,
+    key: &str,
+) -> NeonResult<Option<String>> {
+    match get_defined(cx, settings, key)? {
+        Some(value) => match value.downcast::<JsString, _>(cx) {
+            Ok(value) => Ok(Some(value.value(cx))),
+            Err(_) => cx.throw_type_error(format!(
+                "Field `{}` of option `resize` must be a string",
+                key
+            )),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Reads a list of png chunk names, which are made of four ASCII letters.
+fn get_chunk_names<'a, C: Context<'a>>(
+    cx: &mut C,
+    js_object: Handle<'a, JsObject>,
+    key: &str,
+) -> NeonResult<Option<Vec<String>>> {
+    let value = match get_defined(cx, js_object, key)? {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let values = match value.downcast::<JsArray, _>(cx) {
+        Ok(arr) => arr.to_vec(cx)?,
+        Err(_) => {
+            return cx.throw_type_error(format!(
+                "Field `{}` of option `strip` must be an array of chunk names",
+                key
+            ))
+        }
+    };
+    let mut names = vec![];
+    for value in values {
+        let name = match value.downcast::<JsString, _>(cx) {
+            Ok(name) => name.value(cx),
+            Err(_) => {
+                return cx.throw_type_error(format!(
+                    "Field `{}` of option `strip` must be an array of chunk names",
+                    key
+                ))
+            }
+        };
+        if name.len() != 4 || !name.bytes().all(|byte| byte.is_ascii_alphabetic()) {
+            return cx.throw_range_error(format!("Invalid png chunk name `{}`", name));
+        }
+        names.push(name);
+    }
+    Ok(Some(names))
+}
+
+/// Critical chunks, which every decoder needs, start with an uppercase letter.
+fn is_critical_chunk(name: &str) -> bool {
+    name.starts_with(|first: char| first.is_ascii_uppercase())
+}
+
+fn get_alphas<'a, C: Context<'a>>(
+    cx: &mut C,
+    js_object: Handle<'a, JsObject>,
+) -> NeonResult<Option<IndexSet<AlphaOptim>>> {
+    let value = match get_defined(cx, js_object, "alpha")? {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    // oxipng always needs `NoOp` to be present.
+    let mut alphas = IndexSet::new();
+    alphas.insert(AlphaOptim::NoOp);
+
+    if let Ok(enabled) = value.downcast::<JsBoolean, _>(cx) {
+        if enabled.value(cx) {
+            alphas.extend(vec![
+                AlphaOptim::Black,
+                AlphaOptim::White,
+                AlphaOptim::Up,
+                AlphaOptim::Right,
+                AlphaOptim::Down,
+                AlphaOptim::Left,
+            ]);
+        }
+        return Ok(Some(alphas));
+    }
+
+    let values = match value.downcast::<JsArray, _>(cx) {
+        Ok(arr) => arr.to_vec(cx)?,
+        Err(_) => {
+            return cx.throw_type_error("Option `alpha` must be a boolean or an array of strings")
+        }
+    };
+    for value in values {
+        let name = match value.downcast::<JsString, _>(cx) {
+            Ok(name) => name.value(cx),
+            Err(_) => {
+                return cx
+                    .throw_type_error("Option `alpha` must be a boolean or an array of strings")
+            }
+        };
+        let alpha = match name.as_str() {
+            "black" => AlphaOptim::Black,
+            "white" => AlphaOptim::White,
+            "up" => AlphaOptim::Up,
+            "right" => AlphaOptim::Right,
+            "down" => AlphaOptim::Down,
+            "left" => AlphaOptim::Left,
+            _ => {
+                return cx.throw_range_error(format!(
+                    "Unknown alpha optimisation `{}` in option `alpha`",
+                    name
+                ))
+            }
+        };
+        alphas.insert(alpha);
+    }
+    Ok(Some(alphas))
+}
@@ -0,0 +1,148 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::SystemTime;
+
+/// Counter making the names of the temporary files unique within the process.
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Creates the missing parent directories of `path`, returning the path of the
+/// directory which could not be created on failure.
+pub fn create_parent(path: &str) -> Result<(), (String, io::Error)> {
+    match Path::new(path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => {
+            fs::create_dir_all(parent).map_err(|err| (parent.display().to_string(), err))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Writes `data` to `path`, setting its modification time to `mtime` if given.
+pub fn write(path: &str, data: &[u8], mtime: Option<SystemTime>) -> io::Result<()> {
+    fs::write(path, data)?;
+    if let Some(mtime) = mtime {
+        File::options()
+            .write(true)
+            .open(path)?
+            .set_modified(mtime)?;
+    }
+    Ok(())
+}
+
+/// Atomically replaces the file at `path` with `data`. The data is written to a
+/// temporary file in the same directory, which takes over the permissions of
+/// the original file and is then renamed over it, so the original is never
+/// left truncated if the process dies halfway. The modification time is set to
+/// `mtime` if given.
+pub fn replace(path: &str, data: &[u8], mtime: Option<SystemTime>) -> io::Result<()> {
+    let permissions = fs::metadata(path)?.permissions();
+    write_atomic(Path::new(path), data, Some(permissions), mtime)
+}
+
+/// Writes `data` to a temporary file beside `path` and renames it over `path`,
+/// so readers never see a partially written file.
+pub fn write_atomic(
+    path: &Path,
+    data: &[u8],
+    permissions: Option<fs::Permissions>,
+    mtime: Option<SystemTime>,
+) -> io::Result<()> {
+    let temp_path = temp_path(path);
+    let written = write_temp(&temp_path, data, permissions, mtime)
+        .and_then(|()| fs::rename(&temp_path, path));
+    if written.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    written
+}
+
+fn write_temp(
+    temp_path: &Path,
+    data: &[u8],
+    permissions: Option<fs::Permissions>,
+    mtime: Option<SystemTime>,
+) -> io::Result<()> {
+    let mut file = File::create(temp_path)?;
+    file.write_all(data)?;
+    if let Some(permissions) = permissions {
+        file.set_permissions(permissions)?;
+    }
+    if let Some(mtime) = mtime {
+        file.set_modified(mtime)?;
+    }
+    file.sync_all()
+}
+
+/// Hidden file name beside `path`, unique for this process.
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let id = TEMP_COUNTER.fetch_add(1, Ordering::SeqCst);
+    path.with_file_name(format!(".{}.{}.{}.tmp", name, process::id(), id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+    use std::time::Duration;
+
+    #[test]
+    fn write_sets_mtime() {
+        let dir = TestDir::new("output-write");
+        let path = dir.join("a/out.png");
+        create_parent(&path).unwrap();
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        write(&path, b"data", Some(mtime)).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"data");
+        assert_eq!(fs::metadata(&path).unwrap().modified().unwrap(), mtime);
+    }
+
+    #[test]
+    fn replace_swaps_contents() {
+        let dir = TestDir::new("output-replace");
+        let path = dir.write("image.png", b"original");
+        replace(&path, b"optimised", None).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"optimised");
+        assert_eq!(dir.entries(), ["image.png"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn replace_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TestDir::new("output-permissions");
+        let path = dir.write("image.png", b"original");
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        replace(&path, b"optimised", None).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+    }
+
+    #[test]
+    fn replace_fails_on_missing_file() {
+        let dir = TestDir::new("output-missing");
+        let path = dir.join("image.png");
+        assert_eq!(
+            replace(&path, b"optimised", None).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert!(dir.entries().is_empty());
+    }
+
+    #[test]
+    fn write_atomic_removes_temp_file_on_error() {
+        let dir = TestDir::new("output-cleanup");
+        // A file can not be renamed over a directory which is not empty.
+        dir.write("image.png/inner", b"");
+        let path = dir.path().join("image.png");
+        assert!(write_atomic(&path, b"optimised", None, None).is_err());
+        assert_eq!(dir.entries(), ["image.png"]);
+        assert!(path.is_dir());
+    }
+}
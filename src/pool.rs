@@ -0,0 +1,172 @@
+use crate::error::CompressError;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+/// Order in which the workers pick up the entries. With `largest_first` the
+/// entries are sorted by descending `sizes`, so that the biggest images are
+/// started early and the batch does not end waiting on a single late starter.
+/// Otherwise the entries are processed in the order they were passed.
+pub fn schedule(sizes: Vec<u64>, largest_first: bool) -> Vec<usize> {
+    let mut order = (0..sizes.len()).collect::<Vec<usize>>();
+    if largest_first {
+        // Stable sort, entries of the same size keep their relative order.
+        order.sort_by(|a, b| sizes[*b].cmp(&sizes[*a]));
+    }
+    order
+}
+
+/// Runs `job` for every entry of `items` on a pool of at most `num_threads`
+/// worker threads. The workers share a cursor into `order` and each of them
+/// pulls the next entry as soon as it is done with the previous one, so a few
+/// slow entries never hold up the rest of the batch.
+///
+/// Blocks the calling thread until all of the workers have finished and
+/// returns the outputs of `job` in the order of the entries. Workers stop
+/// picking up new entries once `aborted` is raised, in which case the entries
+/// which were never started are `None`.
+pub fn run_parallel<T, R, F>(
+    items: Arc<Vec<T>>,
+    order: Vec<usize>,
+    num_threads: usize,
+    aborted: Arc<AtomicBool>,
+    job: F,
+) -> Result<Vec<Option<R>>, CompressError>
+where
+    T: Send + Sync + 'static,
+    R: Send + 'static,
+    F: Fn(&T) -> R + Send + Sync + 'static,
+{
+    let order = Arc::new(order);
+    let cursor = Arc::new(AtomicUsize::new(0));
+    let job = Arc::new(job);
+
+    // Never spawn more threads than there are entries to work on.
+    let num_threads = num_threads.min(items.len());
+    let mut handles = vec![];
+    for _ in 0..num_threads {
+        let items = items.clone();
+        let order = order.clone();
+        let cursor = cursor.clone();
+        let aborted = aborted.clone();
+        let job = job.clone();
+        let th = thread::spawn(move || {
+            let mut done = vec![];
+            while !aborted.load(Ordering::SeqCst) {
+                let idx = match order.get(cursor.fetch_add(1, Ordering::SeqCst)) {
+                    Some(idx) => *idx,
+                    None => break,
+                };
+                done.push((idx, job(&items[idx])));
+            }
+            done
+        });
+        handles.push(th);
+    }
+
+    let mut outputs = (0..items.len()).map(|_| None).collect::<Vec<Option<R>>>();
+    let mut panicked = 0;
+    for handle in handles {
+        match handle.join() {
+            Ok(done) => {
+                for (idx, output) in done {
+                    outputs[idx] = Some(output);
+                }
+            }
+            Err(_) => panicked += 1,
+        }
+    }
+    if panicked > 0 {
+        return Err(CompressError::new(
+            "ERR_WORKER_PANICKED",
+            format!("{} compression worker thread(s) panicked", panicked),
+        ));
+    }
+    Ok(outputs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run<F>(
+        items: Vec<u64>,
+        num_threads: usize,
+        aborted: Arc<AtomicBool>,
+        job: F,
+    ) -> Vec<Option<u64>>
+    where
+        F: Fn(&u64) -> u64 + Send + Sync + 'static,
+    {
+        let order = schedule(items.clone(), false);
+        run_parallel(Arc::new(items), order, num_threads, aborted, job).unwrap()
+    }
+
+    #[test]
+    fn schedule_keeps_order_by_default() {
+        assert_eq!(schedule(vec![1, 3, 2], false), [0, 1, 2]);
+    }
+
+    #[test]
+    fn schedule_starts_largest_first() {
+        assert_eq!(schedule(vec![1, 3, 2, 3, 1], true), [1, 3, 2, 0, 4]);
+        assert_eq!(schedule(vec![], true), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn outputs_follow_entry_order() {
+        let items: Vec<u64> = (0..50).collect();
+        let order = schedule(items.clone(), true);
+        let outputs = run_parallel(
+            Arc::new(items),
+            order,
+            4,
+            Arc::new(AtomicBool::new(false)),
+            |item| item * 2,
+        )
+        .unwrap();
+        let expected: Vec<Option<u64>> = (0..50).map(|item| Some(item * 2)).collect();
+        assert_eq!(outputs, expected);
+    }
+
+    #[test]
+    fn more_threads_than_entries() {
+        let aborted = Arc::new(AtomicBool::new(false));
+        assert_eq!(
+            run(vec![1, 2], 16, aborted.clone(), |item| item + 1),
+            [Some(2), Some(3)]
+        );
+        assert_eq!(run(vec![], 4, aborted, |item| item + 1), []);
+    }
+
+    #[test]
+    fn aborted_entries_are_not_started() {
+        let aborted = Arc::new(AtomicBool::new(true));
+        assert_eq!(run(vec![1, 2], 2, aborted, |item| *item), [None, None]);
+
+        let aborted = Arc::new(AtomicBool::new(false));
+        let job_aborted = aborted.clone();
+        let outputs = run(vec![1, 2, 3], 1, aborted, move |item| {
+            job_aborted.store(true, Ordering::SeqCst);
+            *item
+        });
+        assert_eq!(outputs, [Some(1), None, None]);
+    }
+
+    #[test]
+    fn panicking_job_fails_the_batch() {
+        let result = run_parallel(
+            Arc::new(vec![1u64, 2]),
+            vec![0, 1],
+            2,
+            Arc::new(AtomicBool::new(false)),
+            |item| {
+                if *item == 2 {
+                    panic!("job failed");
+                }
+                *item
+            },
+        );
+        assert_eq!(result.unwrap_err().code, "ERR_WORKER_PANICKED");
+    }
+}
@@ -0,0 +1,56 @@
+use crate::CompressResult;
+use neon::prelude::*;
+use std::sync::{Arc, Mutex};
+
+/// Reports the finished entries of a batch to the `onProgress` JS callback.
+/// The worker threads can not call into JS themselves, so every report is sent
+/// over a `Channel` and the callback runs on the main thread with an object of
+/// the following structure.
+///
+/// {
+///  result: CompressResult,
+///  completed: number,
+///  total: number
+/// }
+pub struct ProgressReporter {
+    callback: Arc<Root<JsFunction>>,
+    channel: Channel,
+    completed: Mutex<usize>,
+}
+
+impl ProgressReporter {
+    pub fn new<'a, C: Context<'a>>(cx: &mut C, callback: Handle<JsFunction>) -> Self {
+        ProgressReporter {
+            callback: Arc::new(Root::new(cx, &*callback)),
+            channel: cx.channel(),
+            completed: Mutex::new(0),
+        }
+    }
+
+    /// Queues a call of the callback for a finished entry out of the `total`
+    /// entries of the batch.
+    pub fn report(&self, result: &CompressResult, total: usize) {
+        // Sending while holding the lock keeps the `completed` counters in
+        // increasing order on the JS side.
+        let mut counter = self.completed.lock().unwrap_or_else(|err| err.into_inner());
+        *counter += 1;
+
+        let callback = self.callback.clone();
+        let result = result.clone();
+        let completed = *counter;
+        self.channel.send(move |mut cx| {
+            let callback = callback.to_inner(&mut cx);
+            let event = cx.empty_object();
+            let result = result.to_js_object(&mut cx)?;
+            event.set(&mut cx, "result", result)?;
+            let completed = cx.number(completed as f64);
+            event.set(&mut cx, "completed", completed)?;
+            let total = cx.number(total as f64);
+            event.set(&mut cx, "total", total)?;
+
+            let this = cx.undefined();
+            callback.call(&mut cx, this, vec![event.upcast()])?;
+            Ok(())
+        });
+    }
+}
@@ -0,0 +1,537 @@
+use crate::chunk;
+use crate::decode::{self, Source};
+use crate::options::Lossy;
+use color_quant::NeuQuant;
+use png::{BitDepth, ColorType, Compression, Encoder};
+use std::collections::HashMap;
+
+/// Fraction of the pixels the NeuQuant network is trained with, between 1 for
+/// every pixel and 30 for every 30th.
+const SAMPLE_FACTOR: i32 = 3;
+/// Palette sizes tried in turn until one reaches the maximum quality. NeuQuant
+/// needs at least 64 colours to give good results.
+const PALETTE_SIZES: &[usize] = &[64, 128, 256];
+/// Rounds of k-means refining the palette NeuQuant learnt, which on its own
+/// spends too many entries on the dense parts of the image.
+const KMEANS_ITERATIONS: usize = 5;
+
+/// Weights of the red, green, blue and alpha channel in the colour difference,
+/// the ones of libimagequant.
+const CHANNEL_WEIGHTS: [f64; 4] = [0.5, 1.0, 0.45, 0.625];
+/// Gamma colours are compared in by libimagequant, which is closer to linear
+/// light than the gamma of `INPUT_GAMMA` images.
+const INTERNAL_GAMMA: f64 = 0.5499;
+/// Gamma pngquant assumes for the input, the one of sRGB.
+const INPUT_GAMMA: f64 = 0.45455;
+/// Scale of the errors of libimagequant, applied to `quality_to_mse` as well.
+const MSE_WEIGHT: f64 = 0.45;
+/// Error of a quality of 0, larger than the difference of any two colours.
+const MAX_DIFF: f64 = 1e20;
+
+/// Palette image produced by `quantize`.
+pub struct Quantized {
+    /// The quantised png.
+    pub data: Vec<u8>,
+    /// How close the quantised image is to the original, between 0 and 100.
+    pub quality: u8,
+}
+
+/// Decoded image with every pixel converted to RGBA8.
+struct Image {
+    width: u32,
+    height: u32,
+    rgba: Vec<[u8; 4]>,
+}
+
+/// Number of pixels of every colour of an image.
+type Histogram = HashMap<[u8; 4], u32>;
+
+/// Quantises the truecolor png `data` to a palette of at most 256 colours,
+/// using the fewest colours which reach the maximum quality of `lossy`. Returns
+/// `None` for images which are not truecolor, can not be decoded or already
+/// have no more than 256 colours, and when the quality falls below the
+/// minimum, leaving them to the lossless pass.
+///
+/// The quality is on the scale of pngquant, where 100 is the original image.
+/// Ancillary chunks which do not depend on the pixel data, such as the colour
+/// profile and text, are carried over to the quantised image.
+pub fn quantize(data: &[u8], lossy: &Lossy) -> Option<Quantized> {
+    let image = decode(data)?;
+    let histogram = histogram(&image);
+    // oxipng already turns images with few enough colours into palette ones
+    // without losing anything.
+    if histogram.len() <= 256 {
+        return None;
+    }
+    let mut best = None;
+    for &colors in PALETTE_SIZES {
+        let palette = Palette::train(&image, &histogram, colors);
+        let quality = mse_to_quality(palette.mse(&histogram));
+        best = Some((palette, quality));
+        if quality >= lossy.max_quality {
+            break;
+        }
+    }
+    let (palette, quality) = best?;
+    if quality < lossy.min_quality {
+        return None;
+    }
+    let indices = palette.remap(&image, lossy.dithering);
+    let encoded = encode(&image, palette.colors, indices)?;
+    Some(Quantized {
+        data: chunk::carry_over(data, encoded, &[]),
+        quality,
+    })
+}
+
+fn decode(data: &[u8]) -> Option<Image> {
+    let frame = decode::frame(data, Source::Input).ok()?;
+    // Quantising the frames of an animation separately would break it.
+    if !matches!(frame.color_type, ColorType::Rgb | ColorType::Rgba) || frame.animated {
+        return None;
+    }
+    let rgba = frame
+        .rgba8()
+        .into_iter()
+        .map(|pixel| match pixel {
+            // The color of fully transparent pixels does not matter, so they
+            // can all share a single palette entry.
+            [_, _, _, 0] => [0; 4],
+            pixel => pixel,
+        })
+        .collect();
+    Some(Image {
+        width: frame.width,
+        height: frame.height,
+        rgba,
+    })
+}
+
+fn histogram(image: &Image) -> Histogram {
+    let mut histogram = HashMap::new();
+    for pixel in &image.rgba {
+        *histogram.entry(*pixel).or_insert(0) += 1;
+    }
+    histogram
+}
+
+/// Palette learnt from an image, with the entries prepared for the search of
+/// the closest one.
+struct Palette {
+    colors: Vec<[u8; 4]>,
+    /// Entry of the fully transparent pixels, following the visible ones.
+    transparent: u8,
+    /// The visible entries as compared by `difference`.
+    weighted: Vec<[f64; 4]>,
+    /// Indices of the visible entries sorted by their weighted green channel,
+    /// the one with the largest weight, which bounds the search in `nearest`.
+    by_green: Vec<usize>,
+    gamma: [f64; 256],
+}
+
+impl Palette {
+    /// Trains a palette of `colors` entries on `image`, seeded by NeuQuant and
+    /// refined with k-means on its `histogram`, like libimagequant does.
+    fn train(image: &Image, histogram: &Histogram, colors: usize) -> Self {
+        let has_transparent = histogram.contains_key(&[0; 4]);
+        let visible: Vec<u8> = image
+            .rgba
+            .iter()
+            .filter(|pixel| pixel[3] != 0)
+            .flatten()
+            .copied()
+            .collect();
+        let network_colors = if has_transparent { colors - 1 } else { colors };
+        let network = NeuQuant::new(SAMPLE_FACTOR, network_colors, &visible);
+        let seed = network
+            .color_map_rgba()
+            .chunks_exact(4)
+            .map(|entry| [entry[0], entry[1], entry[2], entry[3]])
+            .collect();
+        let mut palette = Palette::new(seed, has_transparent);
+        for _ in 0..KMEANS_ITERATIONS {
+            palette = palette.refine(histogram);
+        }
+        palette
+    }
+
+    fn new(mut colors: Vec<[u8; 4]>, has_transparent: bool) -> Self {
+        let gamma = gamma_table();
+        let weighted: Vec<[f64; 4]> = colors
+            .iter()
+            .map(|color| to_weighted(&gamma, color))
+            .collect();
+        let mut by_green: Vec<usize> = (0..weighted.len()).collect();
+        by_green.sort_by(|&a, &b| weighted[a][1].total_cmp(&weighted[b][1]));
+        let transparent = colors.len() as u8;
+        if has_transparent {
+            colors.push([0; 4]);
+        }
+        Palette {
+            colors,
+            transparent,
+            weighted,
+            by_green,
+            gamma,
+        }
+    }
+
+    /// Moves every visible entry to the mean of the colours closest to it, one
+    /// round of k-means. Entries which no colour is closest to stay put.
+    fn refine(&self, histogram: &Histogram) -> Self {
+        let mut sums = vec![([0.0; 4], 0.0); self.weighted.len()];
+        for (color, &count) in histogram.iter().filter(|(color, _)| color[3] != 0) {
+            let weighted = to_weighted(&self.gamma, color);
+            let (sum, total) = &mut sums[self.nearest(&weighted)];
+            for (sum, value) in sum.iter_mut().zip(weighted.iter()) {
+                *sum += value * f64::from(count);
+            }
+            *total += f64::from(count);
+        }
+        let colors = sums
+            .iter()
+            .zip(&self.colors)
+            .map(|((sum, total), color)| {
+                if *total == 0.0 {
+                    *color
+                } else {
+                    from_weighted(&sum.map(|value| value / total))
+                }
+            })
+            .collect();
+        Palette::new(colors, self.colors.len() > self.weighted.len())
+    }
+
+    /// Index of the visible entry closest to the `weighted` colour. The
+    /// difference is at least the one of the green channel alone, so the
+    /// search walks away from the closest green in both directions until that
+    /// exceeds the best match.
+    fn nearest(&self, weighted: &[f64; 4]) -> usize {
+        let start = self
+            .by_green
+            .partition_point(|&idx| self.weighted[idx][1] < weighted[1]);
+        let mut best = (f64::INFINITY, 0);
+        self.search(weighted, self.by_green[start..].iter(), &mut best);
+        self.search(weighted, self.by_green[..start].iter().rev(), &mut best);
+        best.1
+    }
+
+    /// Updates the `best` difference and entry with the `candidates`, which
+    /// move away from the green of the `weighted` colour.
+    fn search<'a>(
+        &self,
+        weighted: &[f64; 4],
+        candidates: impl Iterator<Item = &'a usize>,
+        best: &mut (f64, usize),
+    ) {
+        for &idx in candidates {
+            let entry = &self.weighted[idx];
+            if (entry[1] - weighted[1]).powi(2) >= best.0 {
+                break;
+            }
+            let diff = difference(weighted, entry);
+            if diff < best.0 {
+                *best = (diff, idx);
+            }
+        }
+    }
+
+    fn index_of(&self, pixel: &[u8; 4]) -> u8 {
+        if pixel[3] == 0 {
+            self.transparent
+        } else {
+            self.nearest(&to_weighted(&self.gamma, pixel)) as u8
+        }
+    }
+
+    /// Mean squared error of mapping every pixel of the `histogram` to its
+    /// closest entry, which is how pngquant judges the quality of a palette as
+    /// well.
+    fn mse(&self, histogram: &Histogram) -> f64 {
+        let (total, pixels) = histogram.iter().filter(|(color, _)| color[3] != 0).fold(
+            (0.0, 0.0),
+            |(total, pixels), (color, &count)| {
+                let weighted = to_weighted(&self.gamma, color);
+                let diff = difference(&weighted, &self.weighted[self.nearest(&weighted)]);
+                (total + diff * f64::from(count), pixels + f64::from(count))
+            },
+        );
+        // Transparent pixels map to their own entry without any error.
+        let transparent = histogram.get(&[0; 4]).copied().unwrap_or(0);
+        total / (pixels + f64::from(transparent)).max(1.0)
+    }
+
+    /// Maps every pixel of `image` to the palette, diffusing the error of each
+    /// pixel onto its neighbours scaled by `dithering` (Floyd-Steinberg).
+    fn remap(&self, image: &Image, dithering: f32) -> Vec<u8> {
+        let width = image.width as usize;
+        // Errors of the current and the next row, padded by a pixel on each side.
+        let mut errors = vec![[0.0f32; 4]; width + 2];
+        let mut next_errors = vec![[0.0f32; 4]; width + 2];
+        let mut indices = Vec::with_capacity(image.rgba.len());
+        for row in image.rgba.chunks_exact(width) {
+            for (x, pixel) in row.iter().enumerate() {
+                // Transparent pixels stay transparent, whatever the error.
+                if pixel[3] == 0 || dithering == 0.0 {
+                    indices.push(self.index_of(pixel));
+                    continue;
+                }
+                let mut target = [0u8; 4];
+                for channel in 0..4 {
+                    let value = f32::from(pixel[channel]) + errors[x + 1][channel];
+                    // The alpha of a visible pixel must not drop to 0, which
+                    // would map it to the transparent entry.
+                    let min = if channel == 3 { 1.0 } else { 0.0 };
+                    target[channel] = value.round().clamp(min, 255.0) as u8;
+                }
+                let idx = self.index_of(&target);
+                indices.push(idx);
+                for channel in 0..4 {
+                    let error = (f32::from(target[channel])
+                        - f32::from(self.colors[idx as usize][channel]))
+                        * dithering;
+                    errors[x + 2][channel] += error * 7.0 / 16.0;
+                    next_errors[x][channel] += error * 3.0 / 16.0;
+                    next_errors[x + 1][channel] += error * 5.0 / 16.0;
+                    next_errors[x + 2][channel] += error / 16.0;
+                }
+            }
+            errors = std::mem::replace(&mut next_errors, vec![[0.0; 4]; width + 2]);
+        }
+        indices
+    }
+}
+
+/// Maps the samples 0..=255 to 0..1 in the `INTERNAL_GAMMA`.
+fn gamma_table() -> [f64; 256] {
+    let mut table = [0.0; 256];
+    for (value, entry) in table.iter_mut().enumerate() {
+        *entry = (value as f64 / 255.0).powf(INTERNAL_GAMMA / INPUT_GAMMA);
+    }
+    table
+}
+
+/// Colour as compared by libimagequant: in the internal gamma, premultiplied
+/// by its alpha and with every channel scaled by its weight.
+fn to_weighted(gamma: &[f64; 256], pixel: &[u8; 4]) -> [f64; 4] {
+    let alpha = f64::from(pixel[3]) / 255.0;
+    [
+        gamma[pixel[0] as usize] * alpha * CHANNEL_WEIGHTS[0],
+        gamma[pixel[1] as usize] * alpha * CHANNEL_WEIGHTS[1],
+        gamma[pixel[2] as usize] * alpha * CHANNEL_WEIGHTS[2],
+        alpha * CHANNEL_WEIGHTS[3],
+    ]
+}
+
+/// Inverse of `to_weighted`, rounded to the closest RGBA8 colour.
+fn from_weighted(weighted: &[f64; 4]) -> [u8; 4] {
+    let alpha = (weighted[3] / CHANNEL_WEIGHTS[3]).clamp(1.0 / 255.0, 1.0);
+    let mut color = [0; 4];
+    for channel in 0..3 {
+        let value = (weighted[channel] / CHANNEL_WEIGHTS[channel] / alpha).clamp(0.0, 1.0);
+        color[channel] = (value.powf(INPUT_GAMMA / INTERNAL_GAMMA) * 255.0).round() as u8;
+    }
+    color[3] = (alpha * 255.0).round() as u8;
+    color
+}
+
+/// Squared difference between two weighted colours. A difference in alpha
+/// shows against the background, so each channel takes the larger error of
+/// blending onto black and onto white, as libimagequant does.
+fn difference(a: &[f64; 4], b: &[f64; 4]) -> f64 {
+    let alphas = b[3] - a[3];
+    (0..3)
+        .map(|channel| {
+            let black = a[channel] - b[channel];
+            let white = black + alphas;
+            black.powi(2).max(white.powi(2))
+        })
+        .sum()
+}
+
+/// Highest quality whose error budget the `mse` fits into.
+fn mse_to_quality(mse: f64) -> u8 {
+    (1..=100)
+        .rev()
+        .find(|&quality| mse <= quality_to_mse(quality) + 1e-6)
+        .unwrap_or(0)
+}
+
+/// Largest mean squared error allowed at `quality`, following the curve of
+/// libimagequant so the qualities compare to the ones of pngquant.
+fn quality_to_mse(quality: u8) -> f64 {
+    match quality {
+        0 => return MAX_DIFF,
+        100 => return 0.0,
+        _ => {}
+    }
+    let quality = f64::from(quality);
+    let low_quality_fudge = (0.016 / (0.001 + quality) - 0.001).max(0.0);
+    MSE_WEIGHT * (low_quality_fudge + 2.5 / (210.0 + quality).powf(1.2) * (100.1 - quality) / 100.0)
+}
+
+/// Encodes the palette image. Entries which are not fully opaque are moved to
+/// the front, so the `tRNS` chunk only lists those.
+fn encode(image: &Image, palette: Vec<[u8; 4]>, indices: Vec<u8>) -> Option<Vec<u8>> {
+    let mut order: Vec<usize> = (0..palette.len()).collect();
+    order.sort_by_key(|&idx| palette[idx][3] == u8::MAX);
+    let mut new_index = vec![0u8; palette.len()];
+    for (new, &old) in order.iter().enumerate() {
+        new_index[old] = new as u8;
+    }
+    let rgb: Vec<u8> = order
+        .iter()
+        .flat_map(|&idx| palette[idx][..3].to_vec())
+        .collect();
+    let trns: Vec<u8> = order
+        .iter()
+        .map(|&idx| palette[idx][3])
+        .take_while(|&alpha| alpha != u8::MAX)
+        .collect();
+    let indices: Vec<u8> = indices
+        .into_iter()
+        .map(|idx| new_index[idx as usize])
+        .collect();
+
+    let mut data = vec![];
+    {
+        let mut encoder = Encoder::new(&mut data, image.width, image.height);
+        encoder.set_color(ColorType::Indexed);
+        encoder.set_depth(BitDepth::Eight);
+        encoder.set_palette(rgb);
+        if !trns.is_empty() {
+            encoder.set_trns(trns);
+        }
+        // The image is compressed again by oxipng.
+        encoder.set_compression(Compression::Fast);
+        let mut writer = encoder.write_header().ok()?;
+        writer.write_image_data(&indices).ok()?;
+        writer.finish().ok()?;
+    }
+    Some(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Smooth 128x128 gradient with 16384 colours.
+    fn gradient() -> Image {
+        let rgba = (0..128u32)
+            .flat_map(|y| {
+                (0..128u32).map(move |x| [(x * 2) as u8, (y * 2) as u8, (x + y) as u8, 255])
+            })
+            .collect();
+        Image {
+            width: 128,
+            height: 128,
+            rgba,
+        }
+    }
+
+    fn quality(image: &Image, colors: usize) -> u8 {
+        let histogram = histogram(image);
+        mse_to_quality(Palette::train(image, &histogram, colors).mse(&histogram))
+    }
+
+    fn encode_rgba(image: &Image) -> Vec<u8> {
+        let mut data = vec![];
+        let mut encoder = Encoder::new(&mut data, image.width, image.height);
+        encoder.set_color(ColorType::Rgba);
+        encoder.set_depth(BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&image.rgba.concat()).unwrap();
+        writer.finish().unwrap();
+        data
+    }
+
+    #[test]
+    fn quality_follows_libimagequant_curve() {
+        assert_eq!(quality_to_mse(100), 0.0);
+        assert_eq!(quality_to_mse(0), MAX_DIFF);
+        assert!((quality_to_mse(90) - 0.000_121).abs() < 0.000_001);
+        for quality in 0..=100 {
+            assert_eq!(mse_to_quality(quality_to_mse(quality)), quality);
+        }
+        assert_eq!(mse_to_quality(0.0), 100);
+        assert_eq!(mse_to_quality(1.0), 0);
+    }
+
+    #[test]
+    fn difference_weights_channels() {
+        let gamma = gamma_table();
+        let black = to_weighted(&gamma, &[0, 0, 0, 255]);
+        let white = to_weighted(&gamma, &[255, 255, 255, 255]);
+        let expected: f64 = CHANNEL_WEIGHTS[..3]
+            .iter()
+            .map(|weight| weight * weight)
+            .sum();
+        assert!((difference(&black, &white) - expected).abs() < 1e-9);
+        assert_eq!(difference(&white, &white), 0.0);
+        // Transparency shows against a white background, whatever the colour.
+        let clear = to_weighted(&gamma, &[0, 0, 0, 1]);
+        assert!(difference(&black, &clear) > 0.3);
+    }
+
+    #[test]
+    fn weighted_colors_round_trip() {
+        let gamma = gamma_table();
+        for color in [[0, 0, 0, 255], [12, 200, 99, 255], [255, 128, 7, 128]] {
+            assert_eq!(from_weighted(&to_weighted(&gamma, &color)), color);
+        }
+    }
+
+    #[test]
+    fn gradient_quality() {
+        let image = gradient();
+        let qualities: Vec<u8> = PALETTE_SIZES
+            .iter()
+            .map(|&colors| quality(&image, colors))
+            .collect();
+        assert!(qualities.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!((60..=80).contains(&qualities[2]), "{:?}", qualities);
+    }
+
+    #[test]
+    fn noise_quality() {
+        // Every pixel a different, random looking colour.
+        let rgba = (0..128u32 * 128)
+            .map(|idx| {
+                let hash = idx.wrapping_mul(2_654_435_761);
+                [hash as u8, (hash >> 8) as u8, (hash >> 16) as u8, 255]
+            })
+            .collect();
+        let image = Image {
+            width: 128,
+            height: 128,
+            rgba,
+        };
+        assert!(quality(&image, 256) < 30);
+    }
+
+    #[test]
+    fn quantize_skips_few_colors() {
+        let rgba = (0..64u32 * 64)
+            .map(|idx| [(idx % 200) as u8, 0, 0, 255])
+            .collect();
+        let image = Image {
+            width: 64,
+            height: 64,
+            rgba,
+        };
+        assert!(quantize(&encode_rgba(&image), &Lossy::default()).is_none());
+        let quantized = quantize(&encode_rgba(&gradient()), &Lossy::default()).unwrap();
+        assert!((60..=80).contains(&quantized.quality));
+    }
+
+    #[test]
+    fn remap_reaches_black() {
+        let palette = Palette::new(vec![[0, 0, 0, 255], [255, 255, 255, 255]], false);
+        let image = Image {
+            width: 4,
+            height: 1,
+            rgba: vec![[0, 0, 0, 255]; 4],
+        };
+        assert_eq!(palette.remap(&image, 1.0), vec![0; 4]);
+    }
+}
@@ -0,0 +1,520 @@
+use crate::chunk;
+use crate::decode::{self, Source};
+use crate::error::CompressError;
+use crate::options::{Filter, Fit, Resize};
+use png::{BitDepth, ColorType, Compression, Encoder};
+use std::f64::consts::PI;
+use std::io;
+
+/// Ancillary chunks which describe the physical size of the pixels, and so no
+/// longer hold once the image is resampled.
+const SIZE_DEPENDENT_CHUNKS: &[&[u8]] = &[b"pHYs"];
+
+const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Decoded image with its samples widened to floats. Images with an alpha
+/// channel have their colour samples premultiplied, so transparent pixels do
+/// not bleed their colour into their neighbours.
+struct Image {
+    width: usize,
+    height: usize,
+    channels: usize,
+    samples: Vec<f32>,
+}
+
+/// Resamples the png `data` to the size of `resize` with its `filter`, and
+/// encodes it again as png in the colour type and bit depth it was decoded to.
+/// Returns `None` when the image already has that size.
+///
+/// Fails with the error code oxipng would give for data which can not be
+/// decoded, as well as for animations, whose frames can not be resampled
+/// separately, and with `ERR_IMAGE_TOO_LARGE` when the decoded input or the
+/// resampled image would take more than `decode::MAX_INPUT_BYTES`. Ancillary chunks which do not depend on the pixel data or their
+/// size are carried over to the resampled image.
+pub fn resize(data: &[u8], resize: &Resize) -> Result<Option<Vec<u8>>, CompressError> {
+    let frame = decode::frame(data, Source::Input).map_err(|err| decode_error(data, err))?;
+    if frame.animated {
+        return Err(CompressError::new(
+            "ERR_APNG_UNSUPPORTED",
+            "Unable to resize an animated png",
+        ));
+    }
+    let (width, height) = (frame.width, frame.height);
+    let (color_type, bit_depth) = (frame.color_type, frame.bit_depth);
+    let (scaled_width, scaled_height, out_width, out_height) = target_size(width, height, resize);
+    if (scaled_width, scaled_height, out_width, out_height) == (width, height, width, height) {
+        return Ok(None);
+    }
+    // The columns are resampled first, so the intermediate image has the output
+    // width and the input height.
+    let sample_bytes = match bit_depth {
+        BitDepth::Sixteen => 2,
+        _ => 1,
+    };
+    let too_large = [height, out_height].iter().any(|&rows| {
+        (out_width as usize)
+            .checked_mul(rows as usize)
+            .and_then(|pixels| pixels.checked_mul(color_type.samples() * sample_bytes))
+            .is_none_or(|bytes| bytes > decode::MAX_INPUT_BYTES)
+    });
+    if too_large {
+        return Err(CompressError::new(
+            "ERR_IMAGE_TOO_LARGE",
+            format!(
+                "Unable to resize the png to {}x{}, it would take more than {} MiB",
+                out_width,
+                out_height,
+                decode::MAX_INPUT_BYTES >> 20
+            ),
+        ));
+    }
+    let image = Image::new(
+        width,
+        height,
+        color_type.samples(),
+        has_alpha(color_type),
+        bit_depth,
+        &frame.samples,
+    );
+    // The overflow of `Fit::Cover` is cropped equally on both sides.
+    let columns = weights(
+        width,
+        scaled_width,
+        (scaled_width - out_width) / 2,
+        out_width,
+        resize.filter,
+    );
+    let rows = weights(
+        height,
+        scaled_height,
+        (scaled_height - out_height) / 2,
+        out_height,
+        resize.filter,
+    );
+    let pixels = image
+        .resample_columns(&columns)
+        .resample_rows(&rows)
+        .into_bytes(has_alpha(color_type), bit_depth);
+
+    let encoded = encode(out_width, out_height, color_type, bit_depth, &pixels).map_err(|err| {
+        CompressError::new(
+            "ERR_ENCODE_FAILED",
+            format!("Unable to encode the resized png: {}", err),
+        )
+    })?;
+    Ok(Some(chunk::carry_over(
+        data,
+        encoded,
+        SIZE_DEPENDENT_CHUNKS,
+    )))
+}
+
+/// Size the image of `width` by `height` is scaled to for `resize`, followed by
+/// the size it is cropped to, which only differs with `Fit::Cover`.
+fn target_size(width: u32, height: u32, resize: &Resize) -> (u32, u32, u32, u32) {
+    let scale = |size: u32, factor: f64| ((size as f64 * factor).round() as u32).max(1);
+    let (box_width, box_height) = match (resize.width, resize.height) {
+        (Some(box_width), Some(box_height)) => (box_width, box_height),
+        (Some(box_width), None) => {
+            let scaled_height = scale(height, box_width as f64 / width as f64);
+            return (box_width, scaled_height, box_width, scaled_height);
+        }
+        (None, Some(box_height)) => {
+            let scaled_width = scale(width, box_height as f64 / height as f64);
+            return (scaled_width, box_height, scaled_width, box_height);
+        }
+        (None, None) => return (width, height, width, height),
+    };
+    let width_factor = box_width as f64 / width as f64;
+    let height_factor = box_height as f64 / height as f64;
+    match resize.fit {
+        Fit::Fill => (box_width, box_height, box_width, box_height),
+        Fit::Inside => {
+            let factor = width_factor.min(height_factor);
+            let scaled_width = scale(width, factor).min(box_width);
+            let scaled_height = scale(height, factor).min(box_height);
+            (scaled_width, scaled_height, scaled_width, scaled_height)
+        }
+        Fit::Cover => {
+            let factor = width_factor.max(height_factor);
+            let scaled_width = scale(width, factor).max(box_width);
+            let scaled_height = scale(height, factor).max(box_height);
+            (scaled_width, scaled_height, box_width, box_height)
+        }
+    }
+}
+
+fn has_alpha(color_type: ColorType) -> bool {
+    matches!(color_type, ColorType::GrayscaleAlpha | ColorType::Rgba)
+}
+
+/// Contributions of the source pixels to one output pixel along an axis.
+struct Weights {
+    start: usize,
+    values: Vec<f32>,
+}
+
+/// Computes the `Weights` of the `len` output pixels starting at `offset` of an
+/// axis which is scaled from `size` to `scaled` pixels. When shrinking, the
+/// filter is stretched to cover every source pixel.
+fn weights(size: u32, scaled: u32, offset: u32, len: u32, filter: Filter) -> Vec<Weights> {
+    let ratio = size as f64 / scaled as f64;
+    let stretch = ratio.max(1.0);
+    let support = filter.support() * stretch;
+    (offset..offset + len)
+        .map(|pos| {
+            let center = (pos as f64 + 0.5) * ratio;
+            let start = (center - support).floor().max(0.0) as usize;
+            let end = ((center + support).ceil() as usize).min(size as usize);
+            let mut values: Vec<f64> = (start..end)
+                .map(|src| filter.kernel((src as f64 + 0.5 - center) / stretch))
+                .collect();
+            let sum: f64 = values.iter().sum();
+            if sum == 0.0 {
+                // Only possible for the narrow box filter, which then takes the
+                // nearest pixel.
+                let nearest = (center as usize).min(size as usize - 1);
+                return Weights {
+                    start: nearest,
+                    values: vec![1.0],
+                };
+            }
+            values.iter_mut().for_each(|value| *value /= sum);
+            Weights {
+                start,
+                values: values.into_iter().map(|value| value as f32).collect(),
+            }
+        })
+        .collect()
+}
+
+impl Filter {
+    /// Distance from the center beyond which the kernel is zero.
+    fn support(self) -> f64 {
+        match self {
+            Filter::Box => 0.5,
+            Filter::Triangle => 1.0,
+            Filter::CatmullRom | Filter::Mitchell => 2.0,
+            Filter::Lanczos3 => 3.0,
+        }
+    }
+
+    fn kernel(self, x: f64) -> f64 {
+        match self {
+            Filter::Box if (-0.5..0.5).contains(&x) => 1.0,
+            Filter::Box => 0.0,
+            Filter::Triangle => (1.0 - x.abs()).max(0.0),
+            Filter::CatmullRom => cubic(x, 0.0, 0.5),
+            Filter::Mitchell => cubic(x, 1.0 / 3.0, 1.0 / 3.0),
+            Filter::Lanczos3 if x.abs() < 3.0 => sinc(x) * sinc(x / 3.0),
+            Filter::Lanczos3 => 0.0,
+        }
+    }
+}
+
+/// Mitchell–Netravali cubic with the parameters `b` and `c`.
+fn cubic(x: f64, b: f64, c: f64) -> f64 {
+    let x = x.abs();
+    let value = if x < 1.0 {
+        (12.0 - 9.0 * b - 6.0 * c) * x.powi(3)
+            + (-18.0 + 12.0 * b + 6.0 * c) * x.powi(2)
+            + (6.0 - 2.0 * b)
+    } else if x < 2.0 {
+        (-b - 6.0 * c) * x.powi(3)
+            + (6.0 * b + 30.0 * c) * x.powi(2)
+            + (-12.0 * b - 48.0 * c) * x
+            + (8.0 * b + 24.0 * c)
+    } else {
+        0.0
+    };
+    value / 6.0
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+impl Image {
+    /// Widens the decoded `buf` of 8 or 16 bit samples, premultiplying the
+    /// colour samples by the last, alpha, sample when the image has one.
+    fn new(
+        width: u32,
+        height: u32,
+        channels: usize,
+        alpha: bool,
+        bit_depth: BitDepth,
+        buf: &[u8],
+    ) -> Self {
+        let mut samples: Vec<f32> = match bit_depth {
+            BitDepth::Sixteen => buf
+                .chunks_exact(2)
+                .map(|sample| u16::from_be_bytes([sample[0], sample[1]]) as f32)
+                .collect(),
+            _ => buf.iter().map(|&sample| sample as f32).collect(),
+        };
+        if alpha {
+            let max = max_sample(bit_depth);
+            for pixel in samples.chunks_exact_mut(channels) {
+                let (alpha, color) = pixel.split_last_mut().expect("pixels have samples");
+                color.iter_mut().for_each(|sample| *sample *= *alpha / max);
+            }
+        }
+        Image {
+            width: width as usize,
+            height: height as usize,
+            channels,
+            samples,
+        }
+    }
+
+    fn resample_columns(&self, columns: &[Weights]) -> Image {
+        let channels = self.channels;
+        let mut samples = vec![0.0; columns.len() * self.height * channels];
+        let rows = self.samples.chunks_exact(self.width * channels);
+        for (row, out) in rows.zip(samples.chunks_exact_mut(columns.len() * channels)) {
+            for (weights, pixel) in columns.iter().zip(out.chunks_exact_mut(channels)) {
+                for (offset, weight) in weights.values.iter().enumerate() {
+                    let src = (weights.start + offset) * channels;
+                    for (sample, value) in pixel.iter_mut().zip(&row[src..src + channels]) {
+                        *sample += weight * value;
+                    }
+                }
+            }
+        }
+        Image {
+            width: columns.len(),
+            height: self.height,
+            channels,
+            samples,
+        }
+    }
+
+    fn resample_rows(&self, rows: &[Weights]) -> Image {
+        let line = self.width * self.channels;
+        let mut samples = vec![0.0; rows.len() * line];
+        for (weights, out) in rows.iter().zip(samples.chunks_exact_mut(line)) {
+            for (offset, weight) in weights.values.iter().enumerate() {
+                let src = (weights.start + offset) * line;
+                for (sample, value) in out.iter_mut().zip(&self.samples[src..src + line]) {
+                    *sample += weight * value;
+                }
+            }
+        }
+        Image {
+            width: self.width,
+            height: rows.len(),
+            channels: self.channels,
+            samples,
+        }
+    }
+
+    /// Narrows the samples back to `bit_depth`, undoing the premultiplication.
+    /// Filters such as Lanczos3 overshoot at sharp edges, so the samples are
+    /// clamped as well.
+    fn into_bytes(self, alpha: bool, bit_depth: BitDepth) -> Vec<u8> {
+        let max = max_sample(bit_depth);
+        let mut samples = self.samples;
+        if alpha {
+            for pixel in samples.chunks_exact_mut(self.channels) {
+                let (alpha, color) = pixel.split_last_mut().expect("pixels have samples");
+                *alpha = alpha.clamp(0.0, max);
+                let factor = if *alpha > 0.0 { max / *alpha } else { 0.0 };
+                color.iter_mut().for_each(|sample| *sample *= factor);
+            }
+        }
+        let narrow = |sample: &f32| sample.round().clamp(0.0, max);
+        match bit_depth {
+            BitDepth::Sixteen => samples
+                .iter()
+                .flat_map(|sample| (narrow(sample) as u16).to_be_bytes())
+                .collect(),
+            _ => samples.iter().map(|sample| narrow(sample) as u8).collect(),
+        }
+    }
+}
+
+fn max_sample(bit_depth: BitDepth) -> f32 {
+    match bit_depth {
+        BitDepth::Sixteen => u16::MAX as f32,
+        _ => u8::MAX as f32,
+    }
+}
+
+fn encode(
+    width: u32,
+    height: u32,
+    color_type: ColorType,
+    bit_depth: BitDepth,
+    pixels: &[u8],
+) -> Result<Vec<u8>, png::EncodingError> {
+    let mut data = vec![];
+    {
+        let mut encoder = Encoder::new(&mut data, width, height);
+        encoder.set_color(color_type);
+        encoder.set_depth(bit_depth);
+        // The image is compressed again by oxipng, this one is only written
+        // when `minSavings` is not met.
+        encoder.set_compression(Compression::Default);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(pixels)?;
+        writer.finish()?;
+    }
+    Ok(data)
+}
+
+/// Error for `data` which the png decoder rejected, with the code oxipng gives
+/// to such data.
+fn decode_error(data: &[u8], err: png::DecodingError) -> CompressError {
+    let code = match &err {
+        _ if !data.starts_with(SIGNATURE) => "ERR_NOT_PNG",
+        png::DecodingError::LimitsExceeded => "ERR_IMAGE_TOO_LARGE",
+        png::DecodingError::IoError(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+            "ERR_TRUNCATED_DATA"
+        }
+        _ => "ERR_INVALID_DATA",
+    };
+    CompressError::new(
+        code,
+        format!("Unable to decode the png to resize it: {}", err),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resize_to(width: Option<u32>, height: Option<u32>, fit: Fit) -> Resize {
+        Resize {
+            width,
+            height,
+            fit,
+            filter: Filter::Lanczos3,
+        }
+    }
+
+    /// 8x4 rgb png with a `pHYs` and a `tEXt` chunk before its image data.
+    fn tagged_png() -> Vec<u8> {
+        let pixels: Vec<u8> = (0..32u8)
+            .flat_map(|idx| [idx * 8, 255 - idx * 8, 128])
+            .collect();
+        let encoded = encode(8, 4, ColorType::Rgb, BitDepth::Eight, &pixels).unwrap();
+        let mut data = SIGNATURE.to_vec();
+        for raw in chunk::split(&encoded).unwrap() {
+            data.extend_from_slice(raw);
+            if chunk::name(raw) == b"IHDR" {
+                data.extend(chunk::build(b"pHYs", &[0, 0, 11, 19, 0, 0, 11, 19, 1]));
+                data.extend(chunk::build(b"tEXt", b"Title\0tagged"));
+            }
+        }
+        data
+    }
+
+    fn names(data: &[u8]) -> Vec<&[u8]> {
+        chunk::split(data)
+            .unwrap()
+            .into_iter()
+            .map(chunk::name)
+            .collect()
+    }
+
+    #[test]
+    fn target_size_follows_fit() {
+        let inside = resize_to(Some(100), Some(100), Fit::Inside);
+        assert_eq!(target_size(400, 200, &inside), (100, 50, 100, 50));
+        assert_eq!(target_size(200, 400, &inside), (50, 100, 50, 100));
+        let cover = resize_to(Some(100), Some(100), Fit::Cover);
+        assert_eq!(target_size(400, 200, &cover), (200, 100, 100, 100));
+        assert_eq!(target_size(200, 400, &cover), (100, 200, 100, 100));
+        let fill = resize_to(Some(100), Some(30), Fit::Fill);
+        assert_eq!(target_size(400, 200, &fill), (100, 30, 100, 30));
+    }
+
+    #[test]
+    fn target_size_keeps_aspect_ratio() {
+        let width = resize_to(Some(100), None, Fit::Fill);
+        assert_eq!(target_size(400, 200, &width), (100, 50, 100, 50));
+        let height = resize_to(None, Some(100), Fit::Fill);
+        assert_eq!(target_size(400, 200, &height), (200, 100, 200, 100));
+        // A side is never scaled down to nothing.
+        assert_eq!(target_size(1000, 1, &width), (100, 1, 100, 1));
+    }
+
+    #[test]
+    fn weights_are_normalised() {
+        let filters = [
+            Filter::Box,
+            Filter::Triangle,
+            Filter::CatmullRom,
+            Filter::Mitchell,
+            Filter::Lanczos3,
+        ];
+        for filter in filters {
+            for (size, scaled) in [(100, 37), (10, 23), (7, 7)] {
+                for weights in weights(size, scaled, 0, scaled, filter) {
+                    let sum: f32 = weights.values.iter().sum();
+                    assert!((sum - 1.0).abs() < 1e-5, "{:?} sums to {}", filter, sum);
+                    assert!(weights.start + weights.values.len() <= size as usize);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn resize_drops_size_dependent_chunks() {
+        let original = tagged_png();
+        assert!(names(&original).contains(&&b"pHYs"[..]));
+        let resized = resize(&original, &resize_to(Some(4), None, Fit::Inside))
+            .unwrap()
+            .unwrap();
+        assert_eq!(names(&resized), [&b"IHDR"[..], b"tEXt", b"IDAT", b"IEND"]);
+        let frame = decode::frame(&resized, Source::Optimized).unwrap();
+        assert_eq!((frame.width, frame.height), (4, 2));
+    }
+
+    #[test]
+    fn resize_rejects_large_input() {
+        let mut ihdr = vec![];
+        ihdr.extend_from_slice(&20_000u32.to_be_bytes());
+        ihdr.extend_from_slice(&20_000u32.to_be_bytes());
+        ihdr.extend_from_slice(&[8, 6, 0, 0, 0]);
+        let mut data = SIGNATURE.to_vec();
+        data.extend(chunk::build(b"IHDR", &ihdr));
+        data.extend(chunk::build(b"IDAT", &[0x78, 0x01]));
+        data.extend(chunk::build(b"IEND", &[]));
+        let err = resize(&data, &resize_to(Some(10), None, Fit::Inside)).unwrap_err();
+        assert_eq!(err.code, "ERR_IMAGE_TOO_LARGE");
+    }
+
+    #[test]
+    fn resize_rejects_large_output() {
+        let original = tagged_png();
+        let fill = resize_to(Some(100_000), Some(100_000), Fit::Fill);
+        assert_eq!(
+            resize(&original, &fill).unwrap_err().code,
+            "ERR_IMAGE_TOO_LARGE"
+        );
+        // Only the intermediate image of the column pass is too large.
+        let wide = resize_to(Some(30_000_000), Some(1), Fit::Fill);
+        assert_eq!(
+            resize(&original, &wide).unwrap_err().code,
+            "ERR_IMAGE_TOO_LARGE"
+        );
+    }
+
+    #[test]
+    fn carry_over_keeps_pixel_independent_chunks() {
+        let original = tagged_png();
+        let encoded = encode(2, 1, ColorType::Rgb, BitDepth::Eight, &[0; 6]).unwrap();
+        let carried = chunk::carry_over(&original, encoded.clone(), &[]);
+        assert_eq!(
+            names(&carried),
+            [&b"IHDR"[..], b"pHYs", b"tEXt", b"IDAT", b"IEND"]
+        );
+        let carried = chunk::carry_over(&original, encoded, SIZE_DEPENDENT_CHUNKS);
+        assert_eq!(names(&carried), [&b"IHDR"[..], b"tEXt", b"IDAT", b"IEND"]);
+    }
+}
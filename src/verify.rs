@@ -0,0 +1,137 @@
+use crate::decode::{self, Source};
+use crate::error::CompressError;
+
+/// Decodes both the `input` and the `output` png and compares them pixel for
+/// pixel, failing with `ERR_VERIFY_FAILED` if the output does not show the
+/// exact same image. Samples are compared as 16 bit values, so a bit depth
+/// reduction which did not lose any precision passes. Fully transparent pixels
+/// only have to match in their alpha, as the `alpha` optimisations are free to
+/// change their color.
+pub fn verify(input: &[u8], output: &[u8]) -> Result<(), CompressError> {
+    let input = decode::frame(input, Source::Optimized).map_err(|err| {
+        CompressError::new(
+            "ERR_VERIFY_FAILED",
+            format!("Unable to decode the input for verification: {}", err),
+        )
+    })?;
+    let output = decode::frame(output, Source::Optimized).map_err(|err| {
+        CompressError::new(
+            "ERR_VERIFY_FAILED",
+            format!("Unable to decode the output for verification: {}", err),
+        )
+    })?;
+
+    if (input.width, input.height) != (output.width, output.height) {
+        return Err(CompressError::new(
+            "ERR_VERIFY_FAILED",
+            format!(
+                "Output is {}x{} instead of {}x{}",
+                output.width, output.height, input.width, input.height
+            ),
+        ));
+    }
+    let mismatch = input
+        .rgba16()
+        .iter()
+        .zip(output.rgba16().iter())
+        .position(|(a, b)| a != b && !(a[3] == 0 && b[3] == 0));
+    match mismatch {
+        Some(idx) => Err(CompressError::new(
+            "ERR_VERIFY_FAILED",
+            format!(
+                "Output differs from the input at pixel ({}, {})",
+                idx % input.width as usize,
+                idx / input.width as usize
+            ),
+        )),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use png::{BitDepth, ColorType, Encoder};
+
+    fn encode(width: u32, height: u32, color: ColorType, depth: BitDepth, data: &[u8]) -> Vec<u8> {
+        let mut png = vec![];
+        let mut encoder = Encoder::new(&mut png, width, height);
+        encoder.set_color(color);
+        encoder.set_depth(depth);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(data).unwrap();
+        writer.finish().unwrap();
+        png
+    }
+
+    /// 3x2 RGBA8 image whose last pixel is fully transparent.
+    fn rgba(last: [u8; 4]) -> Vec<u8> {
+        let mut pixels = vec![
+            [255, 0, 0, 255],
+            [0, 255, 0, 255],
+            [0, 0, 255, 255],
+            [10, 20, 30, 128],
+            [40, 50, 60, 255],
+        ];
+        pixels.push(last);
+        encode(3, 2, ColorType::Rgba, BitDepth::Eight, &pixels.concat())
+    }
+
+    #[test]
+    fn identical_images_pass() {
+        let image = rgba([1, 2, 3, 0]);
+        assert!(verify(&image, &image).is_ok());
+    }
+
+    #[test]
+    fn lossless_bit_depth_reduction_passes() {
+        let samples: Vec<u8> = [0u8, 17, 128, 255]
+            .iter()
+            .flat_map(|&value| [value; 2])
+            .collect();
+        let sixteen = encode(2, 2, ColorType::Grayscale, BitDepth::Sixteen, &samples);
+        let eight = encode(
+            2,
+            2,
+            ColorType::Grayscale,
+            BitDepth::Eight,
+            &[0, 17, 128, 255],
+        );
+        assert!(verify(&sixteen, &eight).is_ok());
+    }
+
+    #[test]
+    fn lossy_bit_depth_reduction_fails() {
+        let samples = [0, 0, 17, 1, 128, 128, 255, 255];
+        let sixteen = encode(2, 2, ColorType::Grayscale, BitDepth::Sixteen, &samples);
+        let eight = encode(
+            2,
+            2,
+            ColorType::Grayscale,
+            BitDepth::Eight,
+            &[0, 17, 128, 255],
+        );
+        let err = verify(&sixteen, &eight).unwrap_err();
+        assert_eq!(err.message, "Output differs from the input at pixel (1, 0)");
+    }
+
+    #[test]
+    fn color_under_zero_alpha_may_change() {
+        assert!(verify(&rgba([1, 2, 3, 0]), &rgba([0, 0, 0, 0])).is_ok());
+    }
+
+    #[test]
+    fn changed_pixel_fails() {
+        let err = verify(&rgba([1, 2, 3, 0]), &rgba([1, 2, 3, 1])).unwrap_err();
+        assert_eq!(err.code, "ERR_VERIFY_FAILED");
+        assert_eq!(err.message, "Output differs from the input at pixel (2, 1)");
+    }
+
+    #[test]
+    fn size_mismatch_fails() {
+        let other = encode(2, 3, ColorType::Rgba, BitDepth::Eight, &[0; 24]);
+        let err = verify(&rgba([0; 4]), &other).unwrap_err();
+        assert_eq!(err.code, "ERR_VERIFY_FAILED");
+        assert_eq!(err.message, "Output is 2x3 instead of 3x2");
+    }
+}
@@ -0,0 +1,205 @@
+use crate::error::CompressError;
+use crate::options::DirOptions;
+use ignore::WalkBuilder;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Walks the `src` directory of `options` and returns the input and output
+/// path of every file whose path relative to `src` matches the `include` globs
+/// and none of the `exclude` globs, sorted by path. The output mirrors the
+/// relative path into `dest`, or is the input itself when there is no `dest`.
+/// Directories matching an `exclude` glob are not descended into, and neither
+/// is `dest` when it lies within `src`.
+pub fn collect_files(options: &DirOptions) -> Result<Vec<(String, String)>, CompressError> {
+    let src = &options.src;
+    match fs::metadata(src) {
+        Ok(meta) if meta.is_dir() => {}
+        Ok(_) => {
+            return Err(CompressError::new(
+                "ERR_NOT_A_DIRECTORY",
+                format!("{} is not a directory", src.display()),
+            ))
+        }
+        Err(err) => return Err(CompressError::read(&src.display().to_string(), err)),
+    }
+
+    // Outputs of an earlier run must not be picked up as inputs.
+    let skipped_dir = match (fs::canonicalize(src), &options.dest) {
+        (Ok(canonical_src), Some(dest)) => fs::canonicalize(dest).ok().and_then(|dest| {
+            dest.strip_prefix(&canonical_src)
+                .ok()
+                .map(Path::to_path_buf)
+        }),
+        _ => None,
+    };
+    let exclude = options.exclude.clone();
+    let walk_src = src.clone();
+
+    let mut builder = WalkBuilder::new(src);
+    builder
+        .standard_filters(false)
+        .git_ignore(options.gitignore)
+        .parents(options.gitignore)
+        .require_git(false)
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .filter_entry(move |entry| {
+            let relative = match entry.path().strip_prefix(&walk_src) {
+                Ok(relative) if !relative.as_os_str().is_empty() => relative,
+                _ => return true,
+            };
+            let is_dir = entry
+                .file_type()
+                .is_some_and(|file_type| file_type.is_dir());
+            !(is_dir && (exclude.is_match(relative) || skipped_dir.as_deref() == Some(relative)))
+        });
+
+    let mut files = vec![];
+    for entry in builder.build() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => match err.io_error() {
+                Some(io_err) => return Err(walk_error(io_err, &err)),
+                // Malformed `.gitignore` lines are skipped, the same as git does.
+                None => continue,
+            },
+        };
+        if !entry
+            .file_type()
+            .is_some_and(|file_type| file_type.is_file())
+        {
+            continue;
+        }
+        let relative = match entry.path().strip_prefix(src) {
+            Ok(relative) => relative,
+            Err(_) => continue,
+        };
+        if !options.include.is_match(relative) || options.exclude.is_match(relative) {
+            continue;
+        }
+
+        let out = match &options.dest {
+            Some(dest) => dest.join(relative),
+            None => entry.path().to_path_buf(),
+        };
+        match (entry.path().to_str(), out.to_str()) {
+            (Some(input), Some(out)) => files.push((input.to_string(), out.to_string())),
+            _ => {
+                return Err(CompressError::new(
+                    "ERR_INVALID_PATH",
+                    format!("Path {} is not valid unicode", entry.path().display()),
+                ))
+            }
+        }
+    }
+    Ok(files)
+}
+
+fn walk_error(io_err: &io::Error, err: &ignore::Error) -> CompressError {
+    let code = match io_err.kind() {
+        io::ErrorKind::NotFound => "ERR_FILE_NOT_FOUND",
+        io::ErrorKind::PermissionDenied => "ERR_PERMISSION_DENIED",
+        _ => "ERR_READ_FAILED",
+    };
+    CompressError::new(code, format!("Unable to walk directory: {}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_dir::TestDir;
+    use globset::{Glob, GlobSet, GlobSetBuilder};
+
+    fn glob_set(globs: &[&str]) -> GlobSet {
+        let mut builder = GlobSetBuilder::new();
+        for glob in globs {
+            builder.add(Glob::new(glob).unwrap());
+        }
+        builder.build().unwrap()
+    }
+
+    fn dir_options(dir: &TestDir, dest: Option<&str>, exclude: &[&str]) -> DirOptions {
+        DirOptions {
+            src: dir.path().join("src"),
+            dest: dest.map(|dest| dir.path().join(dest)),
+            include: glob_set(&["**/*.png"]),
+            exclude: glob_set(exclude),
+            gitignore: false,
+        }
+    }
+
+    fn relative(dir: &TestDir, files: Vec<(String, String)>) -> Vec<(String, String)> {
+        let root = format!("{}/", dir.path().display());
+        files
+            .into_iter()
+            .map(|(input, out)| (input.replace(&root, ""), out.replace(&root, "")))
+            .collect()
+    }
+
+    fn pairs(files: &[(&str, &str)]) -> Vec<(String, String)> {
+        files
+            .iter()
+            .map(|(input, out)| (input.to_string(), out.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn collects_included_files_in_place() {
+        let dir = TestDir::new("walk-include");
+        dir.write("src/b.png", b"");
+        dir.write("src/a/c.png", b"");
+        dir.write("src/notes.txt", b"");
+        let files = collect_files(&dir_options(&dir, None, &[])).unwrap();
+        assert_eq!(
+            relative(&dir, files),
+            pairs(&[("src/a/c.png", "src/a/c.png"), ("src/b.png", "src/b.png")])
+        );
+    }
+
+    #[test]
+    fn skips_excluded_files_and_directories() {
+        let dir = TestDir::new("walk-exclude");
+        dir.write("src/keep.png", b"");
+        dir.write("src/skip.png", b"");
+        dir.write("src/vendor/lib.png", b"");
+        let options = dir_options(&dir, None, &["skip.png", "vendor"]);
+        let files = collect_files(&options).unwrap();
+        assert_eq!(
+            relative(&dir, files),
+            pairs(&[("src/keep.png", "src/keep.png")])
+        );
+    }
+
+    #[test]
+    fn mirrors_into_dest() {
+        let dir = TestDir::new("walk-dest");
+        dir.write("src/a/b.png", b"");
+        let files = collect_files(&dir_options(&dir, Some("out"), &[])).unwrap();
+        assert_eq!(
+            relative(&dir, files),
+            pairs(&[("src/a/b.png", "out/a/b.png")])
+        );
+    }
+
+    #[test]
+    fn skips_dest_inside_src() {
+        let dir = TestDir::new("walk-nested-dest");
+        dir.write("src/a.png", b"");
+        dir.write("src/min/a.png", b"");
+        let files = collect_files(&dir_options(&dir, Some("src/min"), &[])).unwrap();
+        assert_eq!(
+            relative(&dir, files),
+            pairs(&[("src/a.png", "src/min/a.png")])
+        );
+    }
+
+    #[test]
+    fn rejects_missing_and_non_directory_src() {
+        let dir = TestDir::new("walk-invalid");
+        let err = collect_files(&dir_options(&dir, None, &[])).unwrap_err();
+        assert_eq!(err.code, "ERR_FILE_NOT_FOUND");
+        dir.write("src", b"");
+        let err = collect_files(&dir_options(&dir, None, &[])).unwrap_err();
+        assert_eq!(err.code, "ERR_NOT_A_DIRECTORY");
+    }
+}